@@ -0,0 +1,372 @@
+use crate::{netns_command, IntoNetnsRef, IpFamily, CONNTRACK_PATH};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Addresses, ports and counters of one direction of a tracked connection. Ports are
+/// only present for protocols that have them, counters only when conntrack
+/// accounting is enabled.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConntrackTuple {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+    pub packets: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+/// Connection tracking entry, as listed by `conntrack -L`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConntrackEntry {
+    pub protocol: String,
+    /// Seconds until the entry expires.
+    pub timeout: u64,
+    /// Protocol state such as `ESTABLISHED`, only present for TCP and similar.
+    pub state: Option<String>,
+    pub original: ConntrackTuple,
+    pub reply: ConntrackTuple,
+    /// Status flags such as `ASSURED` or `UNREPLIED`.
+    pub flags: Vec<String>,
+    pub mark: Option<u32>,
+    pub zone: Option<u16>,
+}
+
+#[derive(Default)]
+struct TupleBuilder {
+    src: Option<IpAddr>,
+    dst: Option<IpAddr>,
+    sport: Option<u16>,
+    dport: Option<u16>,
+    packets: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl TupleBuilder {
+    fn build(self) -> Result<ConntrackTuple> {
+        Ok(ConntrackTuple {
+            src: self.src.ok_or_else(|| anyhow!("Missing source address"))?,
+            dst: self
+                .dst
+                .ok_or_else(|| anyhow!("Missing destination address"))?,
+            sport: self.sport,
+            dport: self.dport,
+            packets: self.packets,
+            bytes: self.bytes,
+        })
+    }
+}
+
+impl FromStr for ConntrackEntry {
+    type Err = anyhow::Error;
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut tokens = line.split_whitespace().peekable();
+        let protocol = tokens
+            .next()
+            .ok_or_else(|| anyhow!("Missing protocol"))?
+            .to_string();
+        // protocol number, not needed since we have the name
+        tokens
+            .next()
+            .ok_or_else(|| anyhow!("Missing protocol number"))?;
+        let timeout = tokens
+            .next()
+            .ok_or_else(|| anyhow!("Missing timeout"))?
+            .parse()?;
+        let state = match tokens.peek() {
+            Some(token) if !token.contains('=') && !token.starts_with('[') => {
+                tokens.next().map(|state| state.to_string())
+            }
+            _ => None,
+        };
+        let mut tuples = vec![];
+        let mut current: Option<TupleBuilder> = None;
+        let mut flags = vec![];
+        let mut mark = None;
+        let mut zone = None;
+        for token in tokens {
+            if let Some(flag) = token.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+                flags.push(flag.to_string());
+                continue;
+            }
+            let (key, value) = match token.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            if key == "src" {
+                if let Some(tuple) = current.take() {
+                    tuples.push(tuple);
+                }
+                current = Some(TupleBuilder::default());
+            }
+            match (key, current.as_mut()) {
+                ("mark", _) => mark = Some(value.parse()?),
+                ("zone", _) => zone = Some(value.parse()?),
+                ("src", Some(tuple)) => tuple.src = Some(value.parse()?),
+                ("dst", Some(tuple)) => tuple.dst = Some(value.parse()?),
+                ("sport", Some(tuple)) => tuple.sport = Some(value.parse()?),
+                ("dport", Some(tuple)) => tuple.dport = Some(value.parse()?),
+                ("packets", Some(tuple)) => tuple.packets = Some(value.parse()?),
+                ("bytes", Some(tuple)) => tuple.bytes = Some(value.parse()?),
+                _ => {}
+            }
+        }
+        if let Some(tuple) = current {
+            tuples.push(tuple);
+        }
+        let mut tuples = tuples.into_iter();
+        let original = tuples
+            .next()
+            .ok_or_else(|| anyhow!("Missing original tuple"))?
+            .build()?;
+        let reply = tuples
+            .next()
+            .ok_or_else(|| anyhow!("Missing reply tuple"))?
+            .build()?;
+        Ok(ConntrackEntry {
+            protocol,
+            timeout,
+            state,
+            original,
+            reply,
+            flags,
+            mark,
+            zone,
+        })
+    }
+}
+
+/// Selects connection tracking entries. Addresses and ports refer to the original
+/// direction of the connection. Without a family, IPv4 entries are selected.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct ConntrackFilter {
+    pub family: Option<IpFamily>,
+    pub protocol: Option<String>,
+    pub source: Option<IpAddr>,
+    pub destination: Option<IpAddr>,
+    pub destination_port: Option<u16>,
+    pub mark: Option<u32>,
+}
+
+impl ConntrackFilter {
+    pub fn new() -> Self {
+        ConntrackFilter::default()
+    }
+
+    /// Select entries of an address family.
+    pub fn family(mut self, family: IpFamily) -> Self {
+        self.family = Some(family);
+        self
+    }
+
+    /// Select entries of a protocol such as `tcp` or `udp`.
+    pub fn protocol(mut self, protocol: &str) -> Self {
+        self.protocol = Some(protocol.to_string());
+        self
+    }
+
+    /// Select entries by source address. This also selects the family.
+    pub fn source(mut self, source: IpAddr) -> Self {
+        self.family = Some(IpFamily::of(&source));
+        self.source = Some(source);
+        self
+    }
+
+    /// Select entries by destination address. This also selects the family.
+    pub fn destination(mut self, destination: IpAddr) -> Self {
+        self.family = Some(IpFamily::of(&destination));
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Select entries by destination port. This needs a protocol.
+    pub fn destination_port(mut self, port: u16) -> Self {
+        self.destination_port = Some(port);
+        self
+    }
+
+    /// Select entries by connection mark.
+    pub fn mark(mut self, mark: u32) -> Self {
+        self.mark = Some(mark);
+        self
+    }
+
+    /// Arguments selecting the entries for `conntrack`.
+    pub fn args(&self) -> Result<Vec<String>> {
+        let mut args = vec![];
+        if let Some(family) = self.family {
+            args.push("-f".to_string());
+            args.push(match family {
+                IpFamily::V4 => "ipv4".to_string(),
+                IpFamily::V6 => "ipv6".to_string(),
+            });
+        }
+        if let Some(protocol) = &self.protocol {
+            args.push("-p".to_string());
+            args.push(protocol.clone());
+        }
+        if let Some(source) = &self.source {
+            args.push("-s".to_string());
+            args.push(source.to_string());
+        }
+        if let Some(destination) = &self.destination {
+            args.push("-d".to_string());
+            args.push(destination.to_string());
+        }
+        if let Some(port) = self.destination_port {
+            if self.protocol.is_none() {
+                return Err(anyhow!("Filtering by port needs a protocol"));
+            }
+            args.push("--dport".to_string());
+            args.push(port.to_string());
+        }
+        if let Some(mark) = self.mark {
+            args.push("-m".to_string());
+            args.push(mark.to_string());
+        }
+        Ok(args)
+    }
+}
+
+/// List the connection tracking entries matching a filter.
+pub async fn conntrack_list(
+    netns: impl IntoNetnsRef,
+    filter: &ConntrackFilter,
+) -> Result<Vec<ConntrackEntry>> {
+    let netns = &netns.into_netns_ref();
+    let output = netns_command(netns, CONNTRACK_PATH)
+        .arg("-L")
+        .args(filter.args()?)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error listing conntrack entries in {netns:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let output = String::from_utf8(output.stdout)?;
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            ConntrackEntry::from_str(line)
+                .with_context(|| format!("Parsing conntrack entry {line:?}"))
+        })
+        .collect()
+}
+
+/// Parse the number of deleted entries from the summary conntrack prints.
+fn conntrack_deleted(summary: &str) -> Option<usize> {
+    summary
+        .lines()
+        .find(|line| line.contains("flow entries have been deleted"))
+        .and_then(|line| line.split(": ").last())
+        .and_then(|line| line.split_whitespace().next())
+        .and_then(|count| count.parse().ok())
+}
+
+/// Delete the connection tracking entries matching a filter, so that the affected
+/// flows are evaluated against the current firewall rules again. Returns the number
+/// of deleted entries.
+pub async fn conntrack_delete(netns: impl IntoNetnsRef, filter: &ConntrackFilter) -> Result<usize> {
+    let netns = &netns.into_netns_ref();
+    info!("conntrack_delete({netns:?}, {filter:?})");
+    let output = netns_command(netns, CONNTRACK_PATH)
+        .arg("-D")
+        .args(filter.args()?)
+        .output()
+        .await?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match conntrack_deleted(&stderr) {
+        Some(count) => Ok(count),
+        None if output.status.success() => Ok(0),
+        None => Err(anyhow!(
+            "Error deleting conntrack entries in {netns:?}: {}",
+            stderr.trim()
+        )),
+    }
+}
+
+/// Delete all connection tracking entries.
+pub async fn conntrack_flush(netns: impl IntoNetnsRef) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("conntrack_flush({netns:?})");
+    let output = netns_command(netns, CONNTRACK_PATH)
+        .arg("-F")
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error flushing conntrack entries in {netns:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_conntrack_entry_parse() {
+    let entry = ConntrackEntry::from_str(
+        "tcp      6 431999 ESTABLISHED src=10.0.0.2 dst=1.1.1.1 sport=51234 dport=443 packets=5 bytes=420 src=1.1.1.1 dst=10.0.0.2 sport=443 dport=51234 packets=4 bytes=3000 [ASSURED] mark=42 use=1",
+    )
+    .unwrap();
+    assert_eq!(entry.protocol, "tcp");
+    assert_eq!(entry.timeout, 431999);
+    assert_eq!(entry.state.as_deref(), Some("ESTABLISHED"));
+    assert_eq!(
+        entry.original,
+        ConntrackTuple {
+            src: "10.0.0.2".parse().unwrap(),
+            dst: "1.1.1.1".parse().unwrap(),
+            sport: Some(51234),
+            dport: Some(443),
+            packets: Some(5),
+            bytes: Some(420),
+        }
+    );
+    assert_eq!(entry.reply.sport, Some(443));
+    assert_eq!(entry.reply.bytes, Some(3000));
+    assert_eq!(entry.flags, vec!["ASSURED"]);
+    assert_eq!(entry.mark, Some(42));
+
+    let entry = ConntrackEntry::from_str(
+        "udp      17 29 src=fd00::2 dst=fd00::1 sport=5353 dport=53 [UNREPLIED] src=fd00::1 dst=fd00::2 sport=53 dport=5353 mark=0 zone=3 use=1",
+    )
+    .unwrap();
+    assert_eq!(entry.state, None);
+    assert_eq!(entry.flags, vec!["UNREPLIED"]);
+    assert_eq!(entry.reply.src, "fd00::1".parse::<IpAddr>().unwrap());
+    assert_eq!(entry.reply.packets, None);
+    assert_eq!(entry.zone, Some(3));
+
+    let entry = ConntrackEntry::from_str(
+        "icmp     1 29 src=10.0.0.2 dst=10.0.0.1 type=8 code=0 id=7 src=10.0.0.1 dst=10.0.0.2 type=0 code=0 id=7 mark=0 use=1",
+    )
+    .unwrap();
+    assert_eq!(entry.original.sport, None);
+
+    assert!(ConntrackEntry::from_str("tcp 6 10 ESTABLISHED src=10.0.0.2").is_err());
+}
+
+#[test]
+fn test_conntrack_filter() {
+    let filter = ConntrackFilter::new()
+        .protocol("udp")
+        .destination("fd00::1".parse().unwrap())
+        .destination_port(51820)
+        .mark(7);
+    assert_eq!(
+        filter.args().unwrap(),
+        vec!["-f", "ipv6", "-p", "udp", "-d", "fd00::1", "--dport", "51820", "-m", "7"]
+    );
+    assert!(ConntrackFilter::new().destination_port(80).args().is_err());
+    assert_eq!(
+        conntrack_deleted(
+            "conntrack v1.4.6 (conntrack-tools): 3 flow entries have been deleted.\n"
+        ),
+        Some(3)
+    );
+}
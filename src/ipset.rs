@@ -0,0 +1,403 @@
+use crate::{netns_command, IntoNetnsRef, IpFamily, NetnsRef, Protocol, IPSET_PATH};
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+
+/// Maximum length of a set name, the kernel limit of 32 bytes includes the terminating
+/// null byte.
+pub const IPSET_NAME_MAX: usize = 31;
+
+/// Suffix of the temporary set used by [`ipset_replace`].
+const IPSET_TEMPORARY_SUFFIX: &str = "-new";
+
+/// Check that a set name is accepted by the kernel.
+fn ipset_name_check(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > IPSET_NAME_MAX {
+        return Err(anyhow!(
+            "Invalid ipset name {name:?}, it needs 1 to {IPSET_NAME_MAX} bytes"
+        ));
+    }
+    Ok(())
+}
+
+/// Type of an ipset, which determines what its entries look like.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IpsetType {
+    #[serde(rename = "hash:ip")]
+    HashIp,
+    #[serde(rename = "hash:net")]
+    HashNet,
+    #[serde(rename = "hash:ip,port")]
+    HashIpPort,
+}
+
+impl fmt::Display for IpsetType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpsetType::HashIp => write!(f, "hash:ip"),
+            IpsetType::HashNet => write!(f, "hash:net"),
+            IpsetType::HashIpPort => write!(f, "hash:ip,port"),
+        }
+    }
+}
+
+impl FromStr for IpsetType {
+    type Err = anyhow::Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hash:ip" => Ok(IpsetType::HashIp),
+            "hash:net" => Ok(IpsetType::HashNet),
+            "hash:ip,port" => Ok(IpsetType::HashIpPort),
+            other => Err(anyhow!("Unsupported ipset type {other:?}")),
+        }
+    }
+}
+
+/// Entry of an ipset, matching the type of the set.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IpsetEntry {
+    Ip(IpAddr),
+    Net(IpNet),
+    IpPort(IpAddr, Protocol, u16),
+}
+
+impl IpsetEntry {
+    /// Parse an entry of a set of the given type, as printed by `ipset list`.
+    pub fn parse(set_type: IpsetType, value: &str) -> Result<Self> {
+        let entry = match set_type {
+            IpsetType::HashIp => IpsetEntry::Ip(value.parse()?),
+            IpsetType::HashNet => match value.parse::<IpNet>() {
+                Ok(net) => IpsetEntry::Net(net),
+                Err(_) => IpsetEntry::Net(IpNet::from(value.parse::<IpAddr>()?)),
+            },
+            IpsetType::HashIpPort => {
+                let (addr, port) = value
+                    .split_once(',')
+                    .ok_or_else(|| anyhow!("Missing port in {value:?}"))?;
+                let (protocol, port) = port
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Missing protocol in {value:?}"))?;
+                IpsetEntry::IpPort(addr.parse()?, protocol.parse()?, port.parse()?)
+            }
+        };
+        Ok(entry)
+    }
+
+    /// Type of set this entry belongs in.
+    pub fn set_type(&self) -> IpsetType {
+        match self {
+            IpsetEntry::Ip(_) => IpsetType::HashIp,
+            IpsetEntry::Net(_) => IpsetType::HashNet,
+            IpsetEntry::IpPort(..) => IpsetType::HashIpPort,
+        }
+    }
+
+    /// Address family of this entry.
+    pub fn family(&self) -> IpFamily {
+        match self {
+            IpsetEntry::Ip(addr) | IpsetEntry::IpPort(addr, _, _) => IpFamily::of(addr),
+            IpsetEntry::Net(net) => IpFamily::of(&net.addr()),
+        }
+    }
+}
+
+impl fmt::Display for IpsetEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpsetEntry::Ip(addr) => write!(f, "{addr}"),
+            IpsetEntry::Net(net) => write!(f, "{}", net.trunc()),
+            IpsetEntry::IpPort(addr, protocol, port) => write!(f, "{addr},{protocol}:{port}"),
+        }
+    }
+}
+
+/// Name of the address family as used by ipset.
+fn ipset_family(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "inet",
+        IpFamily::V6 => "inet6",
+    }
+}
+
+/// Set and its entries, as listed by `ipset list`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IpsetInfo {
+    pub name: String,
+    pub set_type: IpsetType,
+    pub family: IpFamily,
+    pub references: usize,
+    pub entries: Vec<IpsetEntry>,
+}
+
+impl FromStr for IpsetInfo {
+    type Err = anyhow::Error;
+    fn from_str(output: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut set_type = None;
+        let mut family = IpFamily::V4;
+        let mut references = 0;
+        let mut entries = vec![];
+        let mut lines = output.lines();
+        for line in lines.by_ref() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key, value.trim()),
+                None => continue,
+            };
+            match key {
+                "Name" => name = Some(value.to_string()),
+                "Type" => set_type = Some(IpsetType::from_str(value)?),
+                "Header" => {
+                    let mut header = value.split_whitespace();
+                    while let Some(option) = header.next() {
+                        if option == "family" {
+                            family = match header.next() {
+                                Some("inet6") => IpFamily::V6,
+                                _ => IpFamily::V4,
+                            };
+                        }
+                    }
+                }
+                "References" => references = value.parse()?,
+                "Members" => break,
+                _ => {}
+            }
+        }
+        let set_type = set_type.ok_or_else(|| anyhow!("Missing ipset type"))?;
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            // entries may be followed by options such as timeouts or counters
+            let entry = line.split_whitespace().next().unwrap_or_default();
+            entries.push(
+                IpsetEntry::parse(set_type, entry)
+                    .with_context(|| format!("Parsing ipset entry {line:?}"))?,
+            );
+        }
+        Ok(IpsetInfo {
+            name: name.ok_or_else(|| anyhow!("Missing ipset name"))?,
+            set_type,
+            family,
+            references,
+            entries,
+        })
+    }
+}
+
+async fn ipset(netns: &Option<NetnsRef>, args: &[&str]) -> Result<String> {
+    let output = netns_command(netns, IPSET_PATH).args(args).output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error running ipset {} in {netns:?}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Create a set, unless it already exists.
+pub async fn ipset_create(
+    netns: impl IntoNetnsRef,
+    name: &str,
+    set_type: IpsetType,
+    family: IpFamily,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("ipset create {name} {set_type} in {netns:?}");
+    ipset_name_check(name)?;
+    let set_type = set_type.to_string();
+    ipset(
+        netns,
+        &[
+            "create",
+            name,
+            &set_type,
+            "family",
+            ipset_family(family),
+            "-exist",
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Add an entry to a set, unless it is already present.
+pub async fn ipset_add(netns: impl IntoNetnsRef, name: &str, entry: &IpsetEntry) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    ipset(netns, &["add", name, &entry.to_string(), "-exist"]).await?;
+    Ok(())
+}
+
+/// Delete an entry from a set, if it is present.
+pub async fn ipset_del(netns: impl IntoNetnsRef, name: &str, entry: &IpsetEntry) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    ipset(netns, &["del", name, &entry.to_string(), "-exist"]).await?;
+    Ok(())
+}
+
+/// List a set and its entries.
+pub async fn ipset_list(netns: impl IntoNetnsRef, name: &str) -> Result<IpsetInfo> {
+    let netns = &netns.into_netns_ref();
+    let output = ipset(netns, &["list", name]).await?;
+    IpsetInfo::from_str(&output).context("Parsing ipset list output")
+}
+
+/// Exchange the contents of two sets of the same type.
+pub async fn ipset_swap(netns: impl IntoNetnsRef, first: &str, second: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("ipset swap {first} {second} in {netns:?}");
+    ipset(netns, &["swap", first, second]).await?;
+    Ok(())
+}
+
+/// Destroy a set. Fails if the set is still referenced by a firewall rule.
+pub async fn ipset_destroy(netns: impl IntoNetnsRef, name: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("ipset destroy {name} in {netns:?}");
+    ipset(netns, &["destroy", name]).await?;
+    Ok(())
+}
+
+/// Render the `ipset restore` input replacing the contents of a `hash:net` set. The
+/// new contents are built in a temporary set which is then swapped in, so the set
+/// never appears partially filled. Long names are shortened for the temporary set to
+/// stay within [`IPSET_NAME_MAX`].
+fn ipset_replace_script(name: &str, family: IpFamily, nets: &[IpNet]) -> Result<String> {
+    ipset_name_check(name)?;
+    let mut end = name
+        .len()
+        .min(IPSET_NAME_MAX - IPSET_TEMPORARY_SUFFIX.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let temporary = format!("{}{IPSET_TEMPORARY_SUFFIX}", &name[..end]);
+    let create = |set: &str| format!("create {set} hash:net family {}", ipset_family(family));
+    let mut lines = vec![
+        create(name),
+        create(&temporary),
+        format!("flush {temporary}"),
+    ];
+    for net in nets {
+        if IpFamily::of(&net.addr()) != family {
+            return Err(anyhow!("Network {net} does not match family {family:?}"));
+        }
+        lines.push(format!("add {temporary} {}", IpsetEntry::Net(*net)));
+    }
+    lines.push(format!("swap {temporary} {name}"));
+    lines.push(format!("destroy {temporary}"));
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+/// Atomically replace the contents of a `hash:net` set, creating it if needed.
+pub async fn ipset_replace(
+    netns: impl IntoNetnsRef,
+    name: &str,
+    family: IpFamily,
+    nets: &[IpNet],
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("ipset_replace({netns:?}, {name}, {})", nets.len());
+    let script = ipset_replace_script(name, family, nets)?;
+    let mut handle = netns_command(netns, IPSET_PATH)
+        .arg("restore")
+        .arg("-exist")
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = handle.stdin.take().unwrap();
+    stdin.write_all(script.as_bytes()).await?;
+    drop(stdin);
+    let output = handle.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error replacing ipset {name} in {netns:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_ipset_list_parse() {
+    let info = IpsetInfo::from_str(
+        "Name: tenants\n\
+         Type: hash:ip,port\n\
+         Revision: 6\n\
+         Header: family inet6 hashsize 1024 maxelem 65536\n\
+         Size in memory: 400\n\
+         References: 2\n\
+         Number of entries: 2\n\
+         Members:\n\
+         fd00::1,tcp:443\n\
+         fd00::2,udp:51820 timeout 60\n",
+    )
+    .unwrap();
+    assert_eq!(info.name, "tenants");
+    assert_eq!(info.set_type, IpsetType::HashIpPort);
+    assert_eq!(info.family, IpFamily::V6);
+    assert_eq!(info.references, 2);
+    assert_eq!(
+        info.entries,
+        vec![
+            IpsetEntry::IpPort("fd00::1".parse().unwrap(), Protocol::Tcp, 443),
+            IpsetEntry::IpPort("fd00::2".parse().unwrap(), Protocol::Udp, 51820),
+        ]
+    );
+    assert_eq!(info.entries[0].to_string(), "fd00::1,tcp:443");
+
+    let info = IpsetInfo::from_str(
+        "Name: allowed\nType: hash:net\nHeader: family inet\nReferences: 0\nMembers:\n10.0.0.0/8\n1.2.3.4\n",
+    )
+    .unwrap();
+    assert_eq!(
+        info.entries,
+        vec![
+            IpsetEntry::Net("10.0.0.0/8".parse().unwrap()),
+            IpsetEntry::Net("1.2.3.4/32".parse().unwrap()),
+        ]
+    );
+    assert!(IpsetInfo::from_str("Name: x\nType: bitmap:port\nMembers:\n").is_err());
+}
+
+#[test]
+fn test_ipset_replace_script() {
+    let nets: Vec<IpNet> = vec!["10.0.0.1/8".parse().unwrap(), "1.2.3.4/32".parse().unwrap()];
+    assert_eq!(
+        ipset_replace_script("allowed", IpFamily::V4, &nets).unwrap(),
+        "create allowed hash:net family inet\n\
+         create allowed-new hash:net family inet\n\
+         flush allowed-new\n\
+         add allowed-new 10.0.0.0/8\n\
+         add allowed-new 1.2.3.4/32\n\
+         swap allowed-new allowed\n\
+         destroy allowed-new\n"
+    );
+    assert!(ipset_replace_script("allowed", IpFamily::V6, &nets).is_err());
+
+    // the temporary set of long names stays within the limit
+    let name = "tenant-0123456789abcdef-allowed";
+    let script = ipset_replace_script(name, IpFamily::V4, &[]).unwrap();
+    assert!(
+        script.contains("swap tenant-0123456789abcdef-all-new tenant-0123456789abcdef-allowed\n")
+    );
+    assert!(ipset_replace_script(&format!("{name}x"), IpFamily::V4, &[]).is_err());
+    assert!(ipset_replace_script("", IpFamily::V4, &[]).is_err());
+}
+
+#[test]
+fn test_ipset_iptables_match() {
+    use crate::IptablesRule;
+    let rule =
+        IptablesRule::new("INPUT", &["-p", "tcp"], "ACCEPT").with_match_set("allowed", "src");
+    assert_eq!(
+        rule,
+        IptablesRule::from_str("-A INPUT -p tcp -m set --match-set allowed src -j ACCEPT").unwrap()
+    );
+}
@@ -0,0 +1,1279 @@
+use crate::{
+    ip6tables_restore, ip6tables_restore_noflush, ip6tables_save, iptables_restore,
+    iptables_restore_noflush, iptables_save, IntoNetnsRef, IpFamily, NetnsRef,
+};
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Prefix of the chains managed by this crate, see [`iptables_apply_owned`].
+pub const IPTABLES_CHAIN_PREFIX: &str = "FRACTAL-";
+
+/// Packet and byte counters of a chain or rule.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct IptablesCounters {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl IptablesCounters {
+    /// Parses counters in the `[packets:bytes]` format used by iptables-save.
+    fn parse(value: &str) -> Result<Self> {
+        let inner = value
+            .strip_prefix('[')
+            .and_then(|value| value.strip_suffix(']'))
+            .ok_or_else(|| anyhow!("Invalid counters {value:?}"))?;
+        let (packets, bytes) = inner
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid counters {value:?}"))?;
+        Ok(IptablesCounters {
+            packets: packets.parse()?,
+            bytes: bytes.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for IptablesCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}:{}]", self.packets, self.bytes)
+    }
+}
+
+/// Chain of an iptables table. Built-in chains have a policy, user-defined chains don't.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IptablesChain {
+    pub name: String,
+    pub policy: Option<String>,
+    pub counters: Option<IptablesCounters>,
+}
+
+impl IptablesChain {
+    /// Create a user-defined chain.
+    pub fn new(name: &str) -> Self {
+        IptablesChain {
+            name: name.to_string(),
+            policy: None,
+            counters: None,
+        }
+    }
+
+    /// Check if this is a built-in chain, such as `INPUT`.
+    pub fn is_builtin(&self) -> bool {
+        self.policy.is_some()
+    }
+}
+
+impl fmt::Display for IptablesChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = self.policy.as_deref().unwrap_or("-");
+        let counters = self.counters.unwrap_or_default();
+        write!(f, ":{} {policy} {counters}", self.name)
+    }
+}
+
+/// Rule of an iptables chain, split into the match arguments and the target.
+///
+/// Rules are compared by their chain, arguments and target only, counters and family
+/// are not part of a rule's identity. Arguments are compared literally, so rules need
+/// to be written the way iptables-save prints them (for example
+/// `-p tcp -m tcp --dport 80`) to be found in a saved state.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq)]
+pub struct IptablesRule {
+    pub chain: String,
+    pub matches: Vec<String>,
+    pub target: Option<String>,
+    pub target_args: Vec<String>,
+    /// Whether the target is reached with `-g` (goto) rather than `-j` (jump).
+    pub goto: bool,
+    pub counters: Option<IptablesCounters>,
+    /// Address family the rule applies to. Rules without a family apply to the family
+    /// of the addresses they mention, or to both if they don't mention any.
+    #[serde(default)]
+    pub family: Option<IpFamily>,
+}
+
+/// Options whose values are addresses, used to infer the family of a rule.
+const ADDRESS_OPTIONS: &[&str] = &[
+    "-s",
+    "--source",
+    "-d",
+    "--destination",
+    "--to-destination",
+    "--to-source",
+    "--to",
+];
+
+fn address_family(value: &str) -> Option<IpFamily> {
+    let value = value.split(',').next()?;
+    let value = value.split('-').next()?;
+    if let Ok(net) = value.parse::<IpNet>() {
+        return Some(IpFamily::of(&net.addr()));
+    }
+    if let Ok(addr) = value.parse::<IpAddr>() {
+        return Some(IpFamily::of(&addr));
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(IpFamily::of(&addr.ip()));
+    }
+    None
+}
+
+impl PartialEq for IptablesRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.chain == other.chain
+            && self.matches == other.matches
+            && self.target == other.target
+            && self.target_args == other.target_args
+            && self.goto == other.goto
+    }
+}
+
+impl IptablesRule {
+    /// Create a rule in a chain that jumps to a target.
+    pub fn new(chain: &str, matches: &[&str], target: &str) -> Self {
+        IptablesRule {
+            chain: chain.to_string(),
+            matches: matches.iter().map(|arg| arg.to_string()).collect(),
+            target: Some(target.to_string()),
+            target_args: vec![],
+            goto: false,
+            counters: None,
+            family: None,
+        }
+    }
+
+    /// Restrict this rule to an address family.
+    pub fn with_family(mut self, family: IpFamily) -> Self {
+        self.family = Some(family);
+        self
+    }
+
+    /// Address family this rule applies to, either set explicitly or inferred from the
+    /// addresses in its arguments. `None` means that it applies to both families.
+    pub fn family(&self) -> Option<IpFamily> {
+        if self.family.is_some() {
+            return self.family;
+        }
+        let args = self.args();
+        args.windows(2)
+            .filter(|pair| ADDRESS_OPTIONS.contains(&pair[0].as_str()))
+            .find_map(|pair| address_family(&pair[1]))
+    }
+
+    /// Match packets against an ipset, for example with the flags `src` or `dst,dst`
+    /// for a `hash:ip,port` set. The match is written the way iptables-save prints it.
+    pub fn with_match_set(mut self, set: &str, flags: &str) -> Self {
+        self.matches.extend(
+            ["-m", "set", "--match-set", set, flags]
+                .iter()
+                .map(|arg| arg.to_string()),
+        );
+        self
+    }
+
+    /// Add arguments for the target of this rule.
+    pub fn with_target_args(mut self, args: &[&str]) -> Self {
+        self.target_args = args.iter().map(|arg| arg.to_string()).collect();
+        self
+    }
+
+    /// Arguments of this rule as passed to iptables, without the chain.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = self.matches.clone();
+        if let Some(target) = &self.target {
+            args.push(if self.goto { "-g" } else { "-j" }.to_string());
+            args.push(target.clone());
+            args.extend(self.target_args.iter().cloned());
+        }
+        args
+    }
+
+    /// Render this rule as an iptables-restore command such as `-A` or `-D`, without
+    /// counters. The position is used by `-I`, starting at one.
+    pub(crate) fn command(&self, command: &str, position: Option<usize>) -> String {
+        let mut line = format!("{command} {}", self.chain);
+        if let Some(position) = position {
+            line.push_str(&format!(" {position}"));
+        }
+        for arg in self.args() {
+            line.push(' ');
+            line.push_str(&quote(&arg));
+        }
+        line
+    }
+
+    fn from_tokens(tokens: Vec<(String, bool)>) -> Result<Self> {
+        let mut tokens = tokens.into_iter().peekable();
+        let counters = match tokens.peek() {
+            Some((token, false)) if token.starts_with('[') => {
+                Some(IptablesCounters::parse(&tokens.next().unwrap().0)?)
+            }
+            _ => None,
+        };
+        match tokens.next() {
+            Some((flag, false)) if flag == "-A" || flag == "--append" => {}
+            other => return Err(anyhow!("Expected -A, got {other:?}")),
+        }
+        let chain = tokens
+            .next()
+            .ok_or_else(|| anyhow!("Missing chain name"))?
+            .0;
+        let mut rule = IptablesRule {
+            chain,
+            matches: vec![],
+            target: None,
+            target_args: vec![],
+            goto: false,
+            counters,
+            family: None,
+        };
+        while let Some((token, quoted)) = tokens.next() {
+            if !quoted && matches!(token.as_str(), "-j" | "--jump" | "-g" | "--goto") {
+                rule.goto = token == "-g" || token == "--goto";
+                rule.target = Some(
+                    tokens
+                        .next()
+                        .ok_or_else(|| anyhow!("Missing target after {token}"))?
+                        .0,
+                );
+                rule.target_args = tokens.map(|(token, _)| token).collect();
+                break;
+            }
+            rule.matches.push(token);
+        }
+        Ok(rule)
+    }
+}
+
+/// Split a line into tokens the way iptables-restore does, honouring double quotes and
+/// backslash escapes. Returns each token along with whether it was quoted.
+fn tokenize(line: &str) -> Result<Vec<(String, bool)>> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut token = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        if chars.peek().is_none() {
+            break;
+        }
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    quoted = true;
+                    in_quotes = !in_quotes;
+                }
+                '\\' if in_quotes => match chars.next() {
+                    Some(escaped) => token.push(escaped),
+                    None => return Err(anyhow!("Trailing backslash in {line:?}")),
+                },
+                c if c.is_whitespace() && !in_quotes => break,
+                c => token.push(c),
+            }
+        }
+        if in_quotes {
+            return Err(anyhow!("Unterminated quote in {line:?}"));
+        }
+        tokens.push((token, quoted));
+    }
+    Ok(tokens)
+}
+
+/// Quote an argument if iptables-restore would otherwise split or misread it.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl FromStr for IptablesRule {
+    type Err = anyhow::Error;
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        IptablesRule::from_tokens(tokenize(line)?)
+    }
+}
+
+impl fmt::Display for IptablesRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(counters) = &self.counters {
+            write!(f, "{counters} ")?;
+        }
+        write!(f, "{}", self.command("-A", None))
+    }
+}
+
+/// Table of an iptables state, such as `filter` or `nat`, with its chains and rules.
+/// Rules are kept in the order iptables evaluates them within each chain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct IptablesTable {
+    pub name: String,
+    pub chains: Vec<IptablesChain>,
+    pub rules: Vec<IptablesRule>,
+}
+
+impl IptablesTable {
+    pub fn new(name: &str) -> Self {
+        IptablesTable {
+            name: name.to_string(),
+            chains: vec![],
+            rules: vec![],
+        }
+    }
+
+    /// Look up a chain by name.
+    pub fn chain(&self, name: &str) -> Option<&IptablesChain> {
+        self.chains.iter().find(|chain| chain.name == name)
+    }
+
+    /// Add a chain, unless a chain with that name already exists.
+    pub fn add_chain(&mut self, chain: IptablesChain) {
+        if self.chain(&chain.name).is_none() {
+            self.chains.push(chain);
+        }
+    }
+
+    /// Iterate over the rules of a chain, in order.
+    pub fn chain_rules<'a>(&'a self, chain: &'a str) -> impl Iterator<Item = &'a IptablesRule> {
+        self.rules.iter().filter(move |rule| rule.chain == chain)
+    }
+
+    /// Position of a rule within its chain, starting at zero.
+    pub fn find_rule(&self, rule: &IptablesRule) -> Option<usize> {
+        self.chain_rules(&rule.chain)
+            .position(|existing| existing == rule)
+    }
+
+    /// Check if the table contains a rule.
+    pub fn contains_rule(&self, rule: &IptablesRule) -> bool {
+        self.find_rule(rule).is_some()
+    }
+
+    /// Append a rule to the end of its chain.
+    pub fn append_rule(&mut self, rule: IptablesRule) {
+        let index = self
+            .rules
+            .iter()
+            .rposition(|existing| existing.chain == rule.chain)
+            .map(|index| index + 1)
+            .unwrap_or(self.rules.len());
+        self.rules.insert(index, rule);
+    }
+
+    /// Insert a rule at a position within its chain, starting at zero. Positions past
+    /// the end of the chain append the rule.
+    pub fn insert_rule(&mut self, position: usize, rule: IptablesRule) {
+        let index = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, existing)| existing.chain == rule.chain)
+            .map(|(index, _)| index)
+            .nth(position);
+        match index {
+            Some(index) => self.rules.insert(index, rule),
+            None => self.append_rule(rule),
+        }
+    }
+
+    /// Remove the first occurrence of a rule. Returns whether the rule was found.
+    pub fn delete_rule(&mut self, rule: &IptablesRule) -> bool {
+        match self.rules.iter().position(|existing| existing == rule) {
+            Some(index) => {
+                self.rules.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for IptablesTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "*{}", self.name)?;
+        for chain in &self.chains {
+            writeln!(f, "{chain}")?;
+        }
+        for rule in &self.rules {
+            writeln!(f, "{rule}")?;
+        }
+        writeln!(f, "COMMIT")
+    }
+}
+
+/// Parsed iptables state, as produced by `iptables-save` and consumed by
+/// `iptables-restore`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct IptablesState {
+    pub tables: Vec<IptablesTable>,
+}
+
+impl IptablesState {
+    /// Look up a table by name.
+    pub fn table(&self, name: &str) -> Option<&IptablesTable> {
+        self.tables.iter().find(|table| table.name == name)
+    }
+
+    /// Look up a table by name, adding an empty one if it does not exist yet.
+    pub fn table_mut(&mut self, name: &str) -> &mut IptablesTable {
+        let index = match self.tables.iter().position(|table| table.name == name) {
+            Some(index) => index,
+            None => {
+                self.tables.push(IptablesTable::new(name));
+                self.tables.len() - 1
+            }
+        };
+        &mut self.tables[index]
+    }
+
+    /// Check if a table contains a rule.
+    pub fn contains_rule(&self, table: &str, rule: &IptablesRule) -> bool {
+        self.table(table)
+            .map(|table| table.contains_rule(rule))
+            .unwrap_or(false)
+    }
+
+    /// The subset of this state that applies to an address family.
+    pub fn for_family(&self, family: IpFamily) -> IptablesState {
+        let mut state = self.clone();
+        for table in &mut state.tables {
+            table
+                .rules
+                .retain(|rule| rule.family().map_or(true, |rule| rule == family));
+        }
+        state
+    }
+
+    /// Render the `iptables-restore --noflush` input which turns the chains owned by
+    /// `prefix` in this state into the ones in `desired`, without touching any other
+    /// chain.
+    ///
+    /// Chains of `desired` starting with `prefix` are created or flushed and filled
+    /// with their rules. Rules of `desired` in other chains, such as the jump from
+    /// `POSTROUTING` to an owned chain, are inserted at the start of their chain if
+    /// they are missing. Rules in other chains that jump to owned chains but are not
+    /// in `desired` are deleted, and owned chains that are not in `desired` are
+    /// removed. Only the tables present in `desired` are touched, so owned chains in
+    /// other tables, such as the NAT chains, are left alone. To remove all owned chains
+    /// of a table, pass an empty table. Returns an empty string if there is nothing to
+    /// change.
+    pub fn owned_update(&self, prefix: &str, desired: &IptablesState) -> String {
+        let empty = IptablesTable::default();
+        let mut output = String::new();
+        for desired in &desired.tables {
+            let name = desired.name.as_str();
+            let current = self.table(name).unwrap_or(&empty);
+            let owned = |chain: &str| chain.starts_with(prefix);
+            let targets_owned = |rule: &IptablesRule| rule.target.as_deref().is_some_and(owned);
+
+            let mut chains: Vec<&str> = vec![];
+            for chain in desired
+                .chains
+                .iter()
+                .map(|chain| chain.name.as_str())
+                .chain(desired.rules.iter().map(|rule| rule.chain.as_str()))
+            {
+                if owned(chain) && !chains.contains(&chain) {
+                    chains.push(chain);
+                }
+            }
+            let stale: Vec<&str> = current
+                .chains
+                .iter()
+                .map(|chain| chain.name.as_str())
+                .filter(|chain| owned(chain) && !chains.contains(chain))
+                .collect();
+            let removed: Vec<&IptablesRule> = current
+                .rules
+                .iter()
+                .filter(|rule| !owned(&rule.chain) && targets_owned(rule))
+                .filter(|rule| !desired.contains_rule(rule))
+                .collect();
+            let added: Vec<&IptablesRule> = desired
+                .rules
+                .iter()
+                .filter(|rule| !owned(&rule.chain) && !current.contains_rule(rule))
+                .collect();
+            if chains.is_empty() && stale.is_empty() && removed.is_empty() && added.is_empty() {
+                continue;
+            }
+
+            let mut lines = vec![format!("*{name}")];
+            for chain in chains.iter().chain(stale.iter()) {
+                lines.push(format!(":{chain} - [0:0]"));
+            }
+            for rule in desired.rules.iter().filter(|rule| owned(&rule.chain)) {
+                lines.push(rule.command("-A", None));
+            }
+            for rule in removed {
+                lines.push(rule.command("-D", None));
+            }
+            for rule in added.iter().rev() {
+                lines.push(rule.command("-I", Some(1)));
+            }
+            for chain in stale {
+                lines.push(format!("-X {chain}"));
+            }
+            lines.push("COMMIT".to_string());
+            for line in lines {
+                output.push_str(&line);
+                output.push('\n');
+            }
+        }
+        output
+    }
+
+    /// Compute the changes that restoring `desired` makes to this state. Only tables
+    /// present in `desired` are compared, since iptables-restore leaves the other
+    /// tables alone. Built-in chains always exist and are not reported as added or
+    /// removed. Rules are compared in order per chain, so a moved rule is reported as
+    /// removed and added again.
+    pub fn diff(&self, desired: &IptablesState) -> IptablesDiff {
+        let mut diff = IptablesDiff::default();
+        let empty = IptablesTable::default();
+        for desired in &desired.tables {
+            let current = self.table(&desired.name).unwrap_or(&empty);
+            for chain in desired.chains.iter().filter(|chain| !chain.is_builtin()) {
+                if current.chain(&chain.name).is_none() {
+                    diff.added_chains
+                        .push((desired.name.clone(), chain.name.clone()));
+                }
+            }
+            for chain in current.chains.iter().filter(|chain| !chain.is_builtin()) {
+                if desired.chain(&chain.name).is_none() {
+                    diff.removed_chains
+                        .push((desired.name.clone(), chain.name.clone()));
+                }
+            }
+            let mut chains: Vec<&str> = vec![];
+            for rule in current.rules.iter().chain(desired.rules.iter()) {
+                if !chains.contains(&rule.chain.as_str()) {
+                    chains.push(&rule.chain);
+                }
+            }
+            for chain in chains {
+                let (added, removed) = rules_diff(
+                    &current.chain_rules(chain).collect::<Vec<_>>(),
+                    &desired.chain_rules(chain).collect::<Vec<_>>(),
+                );
+                if !added.is_empty() || !removed.is_empty() {
+                    diff.chains.push(IptablesChainDiff {
+                        table: desired.name.clone(),
+                        chain: chain.to_string(),
+                        added,
+                        removed,
+                    });
+                }
+            }
+        }
+        diff
+    }
+
+    fn set_family(&mut self, family: IpFamily) {
+        for table in &mut self.tables {
+            for rule in &mut table.rules {
+                rule.family = Some(family);
+            }
+        }
+    }
+}
+
+impl FromStr for IptablesState {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut state = IptablesState::default();
+        let mut current: Option<IptablesTable> = None;
+        for (number, line) in input.lines().enumerate() {
+            let number = number + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('*') {
+                if current.is_some() {
+                    return Err(anyhow!(
+                        "Table {name} started before COMMIT on line {number}"
+                    ));
+                }
+                current = Some(IptablesTable::new(name));
+                continue;
+            }
+            let table = current
+                .as_mut()
+                .ok_or_else(|| anyhow!("Line {number} outside of a table"))?;
+            if line == "COMMIT" {
+                state.tables.push(current.take().unwrap());
+            } else if let Some(chain) = line.strip_prefix(':') {
+                let mut parts = chain.split_whitespace();
+                let name = parts
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing chain name on line {number}"))?;
+                let policy = parts.next().filter(|policy| *policy != "-");
+                let counters = parts
+                    .next()
+                    .map(IptablesCounters::parse)
+                    .transpose()
+                    .with_context(|| format!("Parsing chain counters on line {number}"))?;
+                table.chains.push(IptablesChain {
+                    name: name.to_string(),
+                    policy: policy.map(|policy| policy.to_string()),
+                    counters,
+                });
+            } else {
+                let rule = IptablesRule::from_str(line)
+                    .with_context(|| format!("Parsing rule on line {number}"))?;
+                table.rules.push(rule);
+            }
+        }
+        if let Some(table) = current {
+            return Err(anyhow!("Table {} is missing COMMIT", table.name));
+        }
+        Ok(state)
+    }
+}
+
+impl fmt::Display for IptablesState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for table in &self.tables {
+            write!(f, "{table}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Rules to add to and remove from `current` to turn it into `desired`. Rules of the
+/// longest common subsequence stay in place, all others are changes.
+fn rules_diff(
+    current: &[&IptablesRule],
+    desired: &[&IptablesRule],
+) -> (Vec<IptablesRule>, Vec<IptablesRule>) {
+    let (n, m) = (current.len(), desired.len());
+    // lengths[i][j] is the length of the common subsequence of current[i..], desired[j..]
+    let mut lengths = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i][j] = match current[i] == desired[j] {
+                true => lengths[i + 1][j + 1] + 1,
+                false => lengths[i + 1][j].max(lengths[i][j + 1]),
+            };
+        }
+    }
+    let (mut added, mut removed) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && current[i] == desired[j] {
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            removed.push(current[i].clone());
+            i += 1;
+        } else {
+            added.push(desired[j].clone());
+            j += 1;
+        }
+    }
+    (added, removed)
+}
+
+/// Rules added to and removed from a chain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IptablesChainDiff {
+    pub table: String,
+    pub chain: String,
+    pub added: Vec<IptablesRule>,
+    pub removed: Vec<IptablesRule>,
+}
+
+/// Changes between two iptables states, see [`IptablesState::diff`]. Chains are
+/// identified by their table and name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct IptablesDiff {
+    pub added_chains: Vec<(String, String)>,
+    pub removed_chains: Vec<(String, String)>,
+    pub chains: Vec<IptablesChainDiff>,
+}
+
+impl IptablesDiff {
+    /// Check if there are no changes.
+    pub fn is_empty(&self) -> bool {
+        self.added_chains.is_empty() && self.removed_chains.is_empty() && self.chains.is_empty()
+    }
+}
+
+/// Rendered like a unified diff of iptables-save output, without counters.
+impl fmt::Display for IptablesDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tables: Vec<&str> = vec![];
+        let names = self
+            .added_chains
+            .iter()
+            .chain(self.removed_chains.iter())
+            .map(|(table, _)| table.as_str())
+            .chain(self.chains.iter().map(|chain| chain.table.as_str()));
+        for name in names {
+            if !tables.contains(&name) {
+                tables.push(name);
+            }
+        }
+        for table in tables {
+            writeln!(f, "*{table}")?;
+            for (_, chain) in self.added_chains.iter().filter(|(t, _)| t == table) {
+                writeln!(f, "+:{chain}")?;
+            }
+            for (_, chain) in self.removed_chains.iter().filter(|(t, _)| t == table) {
+                writeln!(f, "-:{chain}")?;
+            }
+            for chain in self.chains.iter().filter(|chain| chain.table == table) {
+                for rule in &chain.removed {
+                    writeln!(f, "-{}", rule.command("-A", None))?;
+                }
+                for rule in &chain.added {
+                    writeln!(f, "+{}", rule.command("-A", None))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fetch and parse the iptables state. Rules are marked as IPv4 rules.
+pub async fn iptables_save_state(netns: impl IntoNetnsRef) -> Result<IptablesState> {
+    let netns = &netns.into_netns_ref();
+    let state = iptables_save(netns).await?;
+    let mut state = IptablesState::from_str(&state).context("Parsing iptables-save output")?;
+    state.set_family(IpFamily::V4);
+    Ok(state)
+}
+
+/// Replace the iptables state of the tables contained in `state` with its IPv4 rules.
+pub async fn iptables_restore_state(netns: impl IntoNetnsRef, state: &IptablesState) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    iptables_restore(netns, &state.for_family(IpFamily::V4).to_string()).await
+}
+
+/// Fetch and parse the ip6tables state. Rules are marked as IPv6 rules.
+pub async fn ip6tables_save_state(netns: impl IntoNetnsRef) -> Result<IptablesState> {
+    let netns = &netns.into_netns_ref();
+    let state = ip6tables_save(netns).await?;
+    let mut state = IptablesState::from_str(&state).context("Parsing ip6tables-save output")?;
+    state.set_family(IpFamily::V6);
+    Ok(state)
+}
+
+/// Replace the ip6tables state of the tables contained in `state` with its IPv6 rules.
+pub async fn ip6tables_restore_state(
+    netns: impl IntoNetnsRef,
+    state: &IptablesState,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    ip6tables_restore(netns, &state.for_family(IpFamily::V6).to_string()).await
+}
+
+/// Replace the IPv4 chains owned by `prefix` with those in `desired`, leaving rules
+/// installed by other software such as Docker in place. See
+/// [`IptablesState::owned_update`] for how `desired` is applied.
+pub async fn iptables_apply_owned(
+    netns: impl IntoNetnsRef,
+    prefix: &str,
+    desired: &IptablesState,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    let current = iptables_save_state(netns).await?;
+    let update = current.owned_update(prefix, &desired.for_family(IpFamily::V4));
+    if update.is_empty() {
+        return Ok(());
+    }
+    iptables_restore_noflush(netns, &update).await
+}
+
+/// Replace the IPv6 chains owned by `prefix` with those in `desired`.
+pub async fn ip6tables_apply_owned(
+    netns: impl IntoNetnsRef,
+    prefix: &str,
+    desired: &IptablesState,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    let current = ip6tables_save_state(netns).await?;
+    let update = current.owned_update(prefix, &desired.for_family(IpFamily::V6));
+    if update.is_empty() {
+        return Ok(());
+    }
+    ip6tables_restore_noflush(netns, &update).await
+}
+
+/// Apply a dual-stack state, restoring its IPv4 rules with iptables and its IPv6 rules
+/// with ip6tables. If restoring the IPv6 rules fails, the previous IPv4 state is
+/// restored so that both families stay consistent.
+pub async fn iptables_restore_dual(netns: impl IntoNetnsRef, state: &IptablesState) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("iptables_restore_dual({netns:?})");
+    let previous = iptables_save(netns).await?;
+    iptables_restore_state(netns, state).await?;
+    if let Err(error) = ip6tables_restore_state(netns, state).await {
+        if let Err(error) = iptables_restore(netns, &previous).await {
+            warn!("Error rolling back iptables state in {netns:?}: {error}");
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+async fn family_save(netns: &Option<NetnsRef>, family: IpFamily) -> Result<String> {
+    match family {
+        IpFamily::V4 => iptables_save(netns).await,
+        IpFamily::V6 => ip6tables_save(netns).await,
+    }
+}
+
+async fn family_restore(netns: &Option<NetnsRef>, family: IpFamily, state: &str) -> Result<()> {
+    match family {
+        IpFamily::V4 => iptables_restore(netns, state).await,
+        IpFamily::V6 => ip6tables_restore(netns, state).await,
+    }
+}
+
+/// Compute the changes restoring the rules of `desired` for an address family would
+/// make, without applying them.
+pub async fn iptables_diff(
+    netns: impl IntoNetnsRef,
+    family: IpFamily,
+    desired: &IptablesState,
+) -> Result<IptablesDiff> {
+    let netns = &netns.into_netns_ref();
+    let current = family_save(netns, family).await?;
+    let current = IptablesState::from_str(&current).context("Parsing iptables-save output")?;
+    Ok(current.diff(&desired.for_family(family)))
+}
+
+/// Saved iptables state of a network namespace, which can be restored later.
+#[derive(Clone, Debug)]
+pub struct IptablesSnapshot {
+    pub netns: Option<NetnsRef>,
+    pub family: IpFamily,
+    pub state: String,
+}
+
+impl IptablesSnapshot {
+    /// Save the current state.
+    pub async fn save(netns: impl IntoNetnsRef, family: IpFamily) -> Result<Self> {
+        let netns = &netns.into_netns_ref();
+        Ok(IptablesSnapshot {
+            netns: netns.clone(),
+            family,
+            state: family_save(netns, family).await?,
+        })
+    }
+
+    /// Restore the saved state.
+    pub async fn restore(&self) -> Result<()> {
+        info!(
+            "iptables snapshot restore({:?}, {:?})",
+            self.netns, self.family
+        );
+        family_restore(&self.netns, self.family, &self.state).await
+    }
+}
+
+/// Apply the rules of `desired` for an address family, returning a snapshot of the
+/// previous state. If applying fails, the previous state is restored.
+pub async fn iptables_apply(
+    netns: impl IntoNetnsRef,
+    family: IpFamily,
+    desired: &IptablesState,
+) -> Result<IptablesSnapshot> {
+    let netns = &netns.into_netns_ref();
+    let snapshot = IptablesSnapshot::save(netns, family).await?;
+    let state = desired.for_family(family).to_string();
+    if let Err(error) = family_restore(netns, family, &state).await {
+        if let Err(error) = snapshot.restore().await {
+            warn!("Error rolling back iptables state in {netns:?}: {error}");
+        }
+        return Err(error);
+    }
+    Ok(snapshot)
+}
+
+/// Applied iptables state which is rolled back unless confirmed in time, see
+/// [`iptables_apply_confirm`].
+#[derive(Debug)]
+pub struct IptablesPendingApply {
+    confirm: oneshot::Sender<()>,
+    handle: JoinHandle<Result<bool>>,
+}
+
+impl IptablesPendingApply {
+    /// Keep the applied state. Returns an error if the timeout already expired and the
+    /// previous state was restored.
+    pub async fn confirm(self) -> Result<()> {
+        let _ = self.confirm.send(());
+        match self.handle.await?? {
+            false => Ok(()),
+            true => Err(anyhow!("Timed out, iptables state was rolled back")),
+        }
+    }
+
+    /// Restore the previous state right away.
+    pub async fn rollback(self) -> Result<()> {
+        drop(self.confirm);
+        self.handle.await??;
+        Ok(())
+    }
+
+    /// Wait for the timeout without confirming. Returns whether the previous state
+    /// was restored, which is always the case unless the pending apply was confirmed.
+    pub async fn wait(self) -> Result<bool> {
+        let IptablesPendingApply { confirm, handle } = self;
+        let result = handle.await?;
+        drop(confirm);
+        result
+    }
+}
+
+/// Apply the rules of `desired` like `iptables-apply` does: the previous state is
+/// restored automatically unless [`IptablesPendingApply::confirm`] is called within
+/// `timeout`, protecting remote machines from locking themselves out. Dropping the
+/// pending apply also restores the previous state.
+pub async fn iptables_apply_confirm(
+    netns: impl IntoNetnsRef,
+    family: IpFamily,
+    desired: &IptablesState,
+    timeout: Duration,
+) -> Result<IptablesPendingApply> {
+    let netns = &netns.into_netns_ref();
+    let snapshot = iptables_apply(netns, family, desired).await?;
+    let (confirm, confirmed) = oneshot::channel();
+    let handle = tokio::spawn(async move {
+        if let Ok(Ok(())) = tokio::time::timeout(timeout, confirmed).await {
+            return Ok(false);
+        }
+        warn!(
+            "iptables state in {:?} not confirmed, rolling back",
+            snapshot.netns
+        );
+        snapshot.restore().await?;
+        Ok(true)
+    });
+    Ok(IptablesPendingApply { confirm, handle })
+}
+
+#[cfg(test)]
+const TEST_STATE: &str = r#"# Generated by iptables-save v1.8.7 on Mon Jun 13 10:00:00 2022
+*nat
+:PREROUTING ACCEPT [12:720]
+:INPUT ACCEPT [0:0]
+:OUTPUT ACCEPT [3:180]
+:POSTROUTING ACCEPT [3:180]
+:DOCKER - [0:0]
+-A PREROUTING -m addrtype --dst-type LOCAL -j DOCKER
+-A POSTROUTING -s 172.17.0.0/16 ! -o docker0 -j MASQUERADE
+-A POSTROUTING -s 10.0.0.0/8 -o eth0 -m comment --comment "fractal \"outbound\" nat" -j MASQUERADE
+-A DOCKER -i docker0 -j RETURN
+-A DOCKER ! -i docker0 -p tcp -m tcp --dport 8080 -j DNAT --to-destination 172.17.0.2:80
+COMMIT
+# Completed on Mon Jun 13 10:00:00 2022
+*filter
+:INPUT ACCEPT [0:0]
+:FORWARD DROP [0:0]
+:OUTPUT ACCEPT [0:0]
+[5:300] -A FORWARD -i wg0 -g ACCEPT
+COMMIT
+"#;
+
+#[test]
+fn test_iptables_state_parse() {
+    let state = IptablesState::from_str(TEST_STATE).unwrap();
+    assert_eq!(state.tables.len(), 2);
+
+    let nat = state.table("nat").unwrap();
+    assert_eq!(nat.chains.len(), 5);
+    assert_eq!(
+        nat.chain("PREROUTING").unwrap().counters,
+        Some(IptablesCounters {
+            packets: 12,
+            bytes: 720
+        })
+    );
+    assert_eq!(nat.chain("DOCKER").unwrap().policy, None);
+    assert_eq!(nat.chain_rules("POSTROUTING").count(), 2);
+
+    let masquerade = nat.chain_rules("POSTROUTING").nth(1).unwrap();
+    assert_eq!(
+        masquerade.matches,
+        vec![
+            "-s",
+            "10.0.0.0/8",
+            "-o",
+            "eth0",
+            "-m",
+            "comment",
+            "--comment",
+            "fractal \"outbound\" nat"
+        ]
+    );
+    assert_eq!(masquerade.target.as_deref(), Some("MASQUERADE"));
+
+    let dnat = nat.chain_rules("DOCKER").nth(1).unwrap();
+    assert_eq!(dnat.target.as_deref(), Some("DNAT"));
+    assert_eq!(dnat.target_args, vec!["--to-destination", "172.17.0.2:80"]);
+
+    let forward = state.table("filter").unwrap().rules[0].clone();
+    assert!(forward.goto);
+    assert_eq!(forward.counters.unwrap().packets, 5);
+}
+
+#[test]
+fn test_iptables_state_roundtrip() {
+    let state = IptablesState::from_str(TEST_STATE).unwrap();
+    let expected: String = TEST_STATE
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    assert_eq!(state.to_string(), expected);
+    assert_eq!(IptablesState::from_str(&state.to_string()).unwrap(), state);
+}
+
+#[test]
+fn test_iptables_state_rules() {
+    let mut state = IptablesState::from_str(TEST_STATE).unwrap();
+    let rule = IptablesRule::new(
+        "POSTROUTING",
+        &["-s", "10.1.0.0/16", "-o", "eth0"],
+        "MASQUERADE",
+    );
+    assert_eq!(
+        rule,
+        IptablesRule::from_str("-A POSTROUTING -s 10.1.0.0/16 -o eth0 -j MASQUERADE").unwrap()
+    );
+    assert!(!state.contains_rule("nat", &rule));
+
+    // insert at the start of the chain
+    state.table_mut("nat").insert_rule(0, rule.clone());
+    assert!(state.contains_rule("nat", &rule));
+    assert_eq!(state.table("nat").unwrap().find_rule(&rule), Some(0));
+    assert_eq!(state.table("nat").unwrap().rules[1], rule);
+
+    // counters don't matter for comparison
+    let mut counted = rule.clone();
+    counted.counters = Some(IptablesCounters {
+        packets: 1,
+        bytes: 2,
+    });
+    assert!(state.contains_rule("nat", &counted));
+
+    // delete it again
+    assert!(state.table_mut("nat").delete_rule(&rule));
+    assert!(!state.table_mut("nat").delete_rule(&rule));
+    assert_eq!(state, IptablesState::from_str(TEST_STATE).unwrap());
+
+    // append to chain and to new table
+    let rule = IptablesRule::new("DOCKER", &[], "RETURN");
+    state.table_mut("nat").append_rule(rule.clone());
+    assert_eq!(state.table("nat").unwrap().find_rule(&rule), Some(2));
+    assert_eq!(state.table("nat").unwrap().rules.last(), Some(&rule));
+    state.table_mut("mangle").append_rule(rule.clone());
+    assert!(state.contains_rule("mangle", &rule));
+}
+
+#[test]
+fn test_iptables_state_errors() {
+    assert!(IptablesState::from_str("-A INPUT -j ACCEPT").is_err());
+    assert!(IptablesState::from_str("*filter\n-A INPUT -j ACCEPT").is_err());
+    assert!(IptablesState::from_str("*filter\n-A INPUT -m comment --comment \"x\nCOMMIT").is_err());
+    assert!(IptablesState::from_str("*filter\n-I INPUT -j ACCEPT\nCOMMIT").is_err());
+    assert!(IptablesState::from_str("*filter\n:INPUT ACCEPT [a:b]\nCOMMIT").is_err());
+}
+
+#[test]
+fn test_iptables_rule_family() {
+    let rule = |line: &str| IptablesRule::from_str(line).unwrap();
+    assert_eq!(
+        rule("-A POSTROUTING -s 10.0.0.0/8 -o eth0 -j MASQUERADE").family(),
+        Some(IpFamily::V4)
+    );
+    assert_eq!(
+        rule("-A FORWARD -d fd00::/64 -j ACCEPT").family(),
+        Some(IpFamily::V6)
+    );
+    assert_eq!(
+        rule("-A PREROUTING -p tcp -m tcp --dport 80 -j DNAT --to-destination [fd00::2]:8080")
+            .family(),
+        Some(IpFamily::V6)
+    );
+    assert_eq!(
+        rule("-A PREROUTING -p tcp -m tcp --dport 80 -j DNAT --to-destination 10.0.0.2:8080")
+            .family(),
+        Some(IpFamily::V4)
+    );
+    assert_eq!(rule("-A INPUT -i wg0 -j ACCEPT").family(), None);
+    assert_eq!(
+        rule("-A INPUT -i wg0 -j ACCEPT")
+            .with_family(IpFamily::V6)
+            .family(),
+        Some(IpFamily::V6)
+    );
+
+    // family does not affect identity
+    assert_eq!(
+        rule("-A INPUT -i wg0 -j ACCEPT").with_family(IpFamily::V6),
+        rule("-A INPUT -i wg0 -j ACCEPT")
+    );
+}
+
+#[test]
+fn test_iptables_state_for_family() {
+    let mut state = IptablesState::default();
+    let filter = state.table_mut("filter");
+    filter.append_rule(IptablesRule::from_str("-A FORWARD -i wg0 -j ACCEPT").unwrap());
+    filter.append_rule(IptablesRule::from_str("-A FORWARD -s 10.0.0.0/8 -j ACCEPT").unwrap());
+    filter.append_rule(IptablesRule::from_str("-A FORWARD -s fd00::/8 -j ACCEPT").unwrap());
+    filter.append_rule(
+        IptablesRule::from_str("-A INPUT -p icmpv6 -j ACCEPT")
+            .unwrap()
+            .with_family(IpFamily::V6),
+    );
+    assert_eq!(
+        state.for_family(IpFamily::V4).to_string(),
+        "*filter\n-A FORWARD -i wg0 -j ACCEPT\n-A FORWARD -s 10.0.0.0/8 -j ACCEPT\nCOMMIT\n"
+    );
+    assert_eq!(
+        state.for_family(IpFamily::V6).to_string(),
+        "*filter\n-A FORWARD -i wg0 -j ACCEPT\n-A FORWARD -s fd00::/8 -j ACCEPT\n-A INPUT -p icmpv6 -j ACCEPT\nCOMMIT\n"
+    );
+}
+
+#[test]
+fn test_iptables_owned_update() {
+    let mut current = IptablesState::from_str(TEST_STATE).unwrap();
+    let nat = current.table_mut("nat");
+    nat.add_chain(IptablesChain::new("FRACTAL-POSTROUTING"));
+    nat.add_chain(IptablesChain::new("FRACTAL-OLD"));
+    nat.append_rule(IptablesRule::new("POSTROUTING", &[], "FRACTAL-POSTROUTING"));
+    nat.append_rule(IptablesRule::new("PREROUTING", &[], "FRACTAL-OLD"));
+    nat.append_rule(IptablesRule::new(
+        "FRACTAL-POSTROUTING",
+        &["-o", "eth0"],
+        "MASQUERADE",
+    ));
+
+    let mut desired = IptablesState::default();
+    let nat = desired.table_mut("nat");
+    nat.add_chain(IptablesChain::new("FRACTAL-POSTROUTING"));
+    nat.append_rule(IptablesRule::new("POSTROUTING", &[], "FRACTAL-POSTROUTING"));
+    nat.append_rule(IptablesRule::new(
+        "FRACTAL-POSTROUTING",
+        &["-s", "10.0.0.0/8", "-o", "eth0"],
+        "MASQUERADE",
+    ));
+    let filter = desired.table_mut("filter");
+    filter.append_rule(IptablesRule::new("FORWARD", &[], "FRACTAL-FORWARD"));
+    filter.append_rule(IptablesRule::new(
+        "FRACTAL-FORWARD",
+        &["-i", "wg0"],
+        "ACCEPT",
+    ));
+
+    // docker rules are left alone, stale owned chains and their jumps are removed
+    assert_eq!(
+        current.owned_update(IPTABLES_CHAIN_PREFIX, &desired),
+        "*nat\n\
+         :FRACTAL-POSTROUTING - [0:0]\n\
+         :FRACTAL-OLD - [0:0]\n\
+         -A FRACTAL-POSTROUTING -s 10.0.0.0/8 -o eth0 -j MASQUERADE\n\
+         -D PREROUTING -j FRACTAL-OLD\n\
+         -X FRACTAL-OLD\n\
+         COMMIT\n\
+         *filter\n\
+         :FRACTAL-FORWARD - [0:0]\n\
+         -A FRACTAL-FORWARD -i wg0 -j ACCEPT\n\
+         -I FORWARD 1 -j FRACTAL-FORWARD\n\
+         COMMIT\n"
+    );
+
+    // owned chains of tables missing in the desired state are kept
+    let mut filter = IptablesState::default();
+    filter.table_mut("filter");
+    assert_eq!(current.owned_update(IPTABLES_CHAIN_PREFIX, &filter), "");
+
+    // an empty table removes all owned chains
+    let mut nat = IptablesState::default();
+    nat.table_mut("nat");
+    assert_eq!(
+        current.owned_update(IPTABLES_CHAIN_PREFIX, &nat),
+        "*nat\n\
+         :FRACTAL-POSTROUTING - [0:0]\n\
+         :FRACTAL-OLD - [0:0]\n\
+         -D PREROUTING -j FRACTAL-OLD\n\
+         -D POSTROUTING -j FRACTAL-POSTROUTING\n\
+         -X FRACTAL-POSTROUTING\n\
+         -X FRACTAL-OLD\n\
+         COMMIT\n"
+    );
+
+    // nothing to do without owned chains
+    let state = IptablesState::from_str(TEST_STATE).unwrap();
+    assert_eq!(
+        state.owned_update(IPTABLES_CHAIN_PREFIX, &IptablesState::default()),
+        ""
+    );
+}
+
+#[test]
+fn test_iptables_state_diff() {
+    let current = IptablesState::from_str(TEST_STATE).unwrap();
+    assert!(current.diff(&current).is_empty());
+
+    let mut desired = current.clone();
+    let nat = desired.table_mut("nat");
+    nat.delete_rule(&IptablesRule::new("DOCKER", &["-i", "docker0"], "RETURN"));
+    nat.add_chain(IptablesChain::new("FRACTAL"));
+    nat.append_rule(IptablesRule::new("POSTROUTING", &[], "FRACTAL"));
+    nat.chains.retain(|chain| chain.name != "OUTPUT");
+    desired.tables.retain(|table| table.name == "nat");
+
+    // the filter table is not part of the desired state and not compared, missing
+    // built-in chains are not removed
+    let diff = current.diff(&desired);
+    assert_eq!(
+        diff.added_chains,
+        vec![("nat".to_string(), "FRACTAL".to_string())]
+    );
+    assert!(diff.removed_chains.is_empty());
+    assert_eq!(diff.chains.len(), 2);
+    assert_eq!(
+        diff.to_string(),
+        "*nat\n\
+         +:FRACTAL\n\
+         +-A POSTROUTING -j FRACTAL\n\
+         --A DOCKER -i docker0 -j RETURN\n"
+    );
+
+    // reordering rules is a change, removing user-defined chains as well
+    let mut desired = current.clone();
+    let nat = desired.table_mut("nat");
+    nat.rules.swap(1, 2);
+    nat.chains.retain(|chain| chain.name != "DOCKER");
+    desired.tables.retain(|table| table.name == "nat");
+    let diff = current.diff(&desired);
+    assert_eq!(
+        diff.removed_chains,
+        vec![("nat".to_string(), "DOCKER".to_string())]
+    );
+    assert_eq!(
+        diff.to_string(),
+        "*nat\n\
+         -:DOCKER\n\
+         --A POSTROUTING -s 172.17.0.0/16 ! -o docker0 -j MASQUERADE\n\
+         +-A POSTROUTING -s 172.17.0.0/16 ! -o docker0 -j MASQUERADE\n"
+    );
+}
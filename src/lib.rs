@@ -1,5 +1,35 @@
 mod types;
 pub use types::*;
+mod iptables;
+pub use iptables::*;
+mod conntrack;
+pub use conntrack::*;
+mod ipset;
+pub use ipset::*;
+mod monitor;
+pub use monitor::*;
+mod nat;
+pub use nat::*;
+mod netns;
+pub use netns::*;
+mod nftables;
+pub use nftables::*;
+mod nginx;
+pub use nginx::*;
+mod nginx_config;
+pub use nginx_config::*;
+mod reverse_proxy;
+pub use reverse_proxy::*;
+mod wireguard_config;
+pub use wireguard_config::*;
+mod wireguard_monitor;
+pub use wireguard_monitor::*;
+mod wireguard_resolve;
+pub use wireguard_resolve::*;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::*;
 #[cfg(test)]
 mod tests;
 
@@ -7,15 +37,32 @@ use anyhow::{anyhow, Context, Result};
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use log::*;
 use serde::Deserialize;
-use std::net::IpAddr;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
+use wireguard_keys::{Privkey, Pubkey, Secret};
 
-pub const IPTABLES_SAVE_PATH: &'static str = "iptables-save";
-pub const IPTABLES_RESTORE_PATH: &'static str = "iptables-restore";
-pub const IP_PATH: &'static str = "ip";
+pub const IPTABLES_SAVE_PATH: &str = "iptables-save";
+pub const IPTABLES_RESTORE_PATH: &str = "iptables-restore";
+pub const IP6TABLES_SAVE_PATH: &str = "ip6tables-save";
+pub const IP6TABLES_RESTORE_PATH: &str = "ip6tables-restore";
+pub const NFT_PATH: &str = "nft";
+pub const IPSET_PATH: &str = "ipset";
+pub const CONNTRACK_PATH: &str = "conntrack";
+pub const NGINX_PATH: &str = "nginx";
+pub const NGINX_PID_PATH: &str = "/run/nginx.pid";
+pub const KILL_PATH: &str = "kill";
+pub const HAPROXY_PATH: &str = "haproxy";
+pub const IP_PATH: &str = "ip";
+pub const NSENTER_PATH: &str = "nsenter";
+pub const NETNS_RUN_PATH: &str = "/var/run/netns";
+pub const WG_PATH: &str = "wg";
 
 /// Adds a network namespace. This creates a new, isolated network namespace
 /// with nothing but the loopback interface in it.
@@ -36,20 +83,41 @@ pub async fn netns_add(name: &str) -> Result<()> {
     }
 }
 
-/// Checks if a network namespaces exists.
-/// TODO: use `ip --json netns list` here.
+/// Checks if a network namespace exists. A dangling mount point, left behind when a
+/// namespace was unmounted without removing its file, does not count as existing.
 pub async fn netns_exists(name: &str) -> Result<bool> {
-    let success = Command::new(IP_PATH)
-        .arg("netns")
-        .arg("exec")
-        .arg(name)
-        .arg("/bin/true")
-        .stderr(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .status()
-        .await?
-        .success();
-    Ok(success)
+    match netns_inode(&Path::new(NETNS_RUN_PATH).join(name)).await {
+        Ok(inode) => Ok(inode.is_some()),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error).with_context(|| format!("Checking if netns {name} exists")),
+    }
+}
+
+/// Inode of the network namespace mounted at `path`, or `None` if the file is not a
+/// namespace mount. Namespace files all live on the nsfs filesystem, which is detected
+/// by comparing the device with the one of the current namespace.
+async fn netns_inode(path: &Path) -> std::io::Result<Option<u64>> {
+    let nsfs = tokio::fs::metadata("/proc/self/ns/net").await?;
+    let metadata = tokio::fs::metadata(path).await?;
+    Ok((metadata.dev() == nsfs.dev()).then(|| metadata.ino()))
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_netns_inode() {
+    let current = std::fs::metadata("/proc/self/ns/net").unwrap();
+    assert_eq!(
+        netns_inode(Path::new("/proc/self/ns/net")).await.unwrap(),
+        Some(current.ino())
+    );
+    let file = std::env::temp_dir().join(format!("netns-inode-{}", std::process::id()));
+    std::fs::write(&file, "").unwrap();
+    assert_eq!(netns_inode(&file).await.unwrap(), None);
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(
+        netns_inode(&file).await.unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
 }
 
 /// Delete a network namespace. This will also delete any network interfaces contained therein.
@@ -70,6 +138,28 @@ pub async fn netns_del(name: &str) -> Result<()> {
     }
 }
 
+/// Give the network namespace of a process a name, by bind-mounting it under
+/// [`NETNS_RUN_PATH`]. This makes namespaces of containers usable with functions which
+/// only take namespace names. Deleting the name with [`netns_del`] does not affect the
+/// process.
+pub async fn netns_attach(name: &str, pid: u32) -> Result<()> {
+    info!("netns attach {name} {pid}");
+    let output = Command::new(IP_PATH)
+        .arg("netns")
+        .arg("attach")
+        .arg(name)
+        .arg(pid.to_string())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error attaching netns of process {pid} as {name}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
 /// Write file into network namespace config folder.
 pub async fn netns_write_file(netns: &str, filename: &Path, data: &str) -> Result<()> {
     let mut path = PathBuf::from("/etc/netns");
@@ -83,32 +173,149 @@ pub async fn netns_write_file(netns: &str, filename: &Path, data: &str) -> Resul
     Ok(())
 }
 
-/// List all network namespaces.
+/// Write secret file into network namespace config folder. The file is only readable by
+/// its owner and is written to a temporary file which is renamed into place, so readers
+/// never see partially written data.
+pub async fn netns_write_file_secret(netns: &str, filename: &Path, data: &str) -> Result<()> {
+    let mut path = PathBuf::from("/etc/netns");
+    path.push(netns);
+    path.push(filename);
+    write_file_secret(&path, data).await
+}
+
+/// Write a file only readable by its owner. Missing parent directories are created only
+/// accessible by their owner, too.
+async fn write_file_secret(path: &Path, data: &str) -> Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("Missing parent directory of {path:?}"))?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Missing file name in {path:?}"))?;
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(parent)
+        .await
+        .with_context(|| format!("Creating {parent:?}"))?;
+    let temp = parent.join(format!(".{}.tmp", name.to_string_lossy()));
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp)
+        .await?;
+    file.write_all(data.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&temp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_write_file_secret() {
+    use std::os::unix::fs::PermissionsExt;
+    let base = std::env::temp_dir().join(format!("write-secret-{}", std::process::id()));
+    let path = base.join("wireguard/wg0.key");
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    write_file_secret(&path, "secret").await.unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "secret");
+    assert_eq!(mode(&path), 0o600);
+    assert_eq!(mode(&base), 0o700);
+    assert_eq!(mode(path.parent().unwrap()), 0o700);
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+/// List all network namespaces, including dangling mount points of namespaces which
+/// no longer exist.
 pub async fn netns_list() -> Result<Vec<NetnsItem>> {
-    let output = Command::new(IP_PATH)
+    let mut items = vec![];
+    for mut item in netns_list_in(&None).await? {
+        item.path = Path::new(NETNS_RUN_PATH).join(&item.name);
+        item.inode = match netns_inode(&item.path).await {
+            Ok(inode) => inode,
+            // deleted since it was listed
+            Err(error) if error.kind() == ErrorKind::NotFound => continue,
+            Err(error) => {
+                return Err(error).with_context(|| format!("Checking netns {}", item.name))
+            }
+        };
+        item.alive = item.inode.is_some();
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// List all network namespaces, with the IDs they have in `netns`.
+async fn netns_list_in(netns: &Option<NetnsRef>) -> Result<Vec<NetnsItem>> {
+    let output = ip_command(netns)
         .arg("--json")
         .arg("netns")
         .arg("list")
         .output()
         .await?;
     if !output.status.success() {
-        return Err(anyhow!("Error fetching wireguard stats: {output:?}"));
+        return Err(anyhow!("Error listing netns in {netns:?}: {output:?}"));
     }
     let output = String::from_utf8(output.stdout).context("Parsing command output as string")?;
     let mut items: Vec<NetnsItem> = vec![];
-    if output.len() > 0 {
+    if !output.is_empty() {
         items = serde_json::from_str(&output).context("Pasing netns list output as JSON")?;
     }
     Ok(items)
 }
 
+/// Assign an ID to the network namespace `name`, as seen from `netns`. Network
+/// namespace IDs are local to the namespace they are assigned in, links such as veth
+/// interfaces refer to the namespace of their peer by them.
+pub async fn netns_set_id(netns: impl IntoNetnsRef, name: &str, id: usize) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("netns set {name} {id} in {netns:?}");
+    let output = ip_command(netns)
+        .arg("netns")
+        .arg("set")
+        .arg(name)
+        .arg(id.to_string())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error setting id of netns {name} in {netns:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Get the ID of the network namespace `name`, as seen from `netns`. Returns `None` if
+/// no ID has been assigned.
+pub async fn netns_get_id(netns: impl IntoNetnsRef, name: &str) -> Result<Option<usize>> {
+    let netns = &netns.into_netns_ref();
+    let items = netns_list_in(netns).await?;
+    match items.into_iter().find(|item| item.name == name) {
+        Some(item) => Ok(item.id),
+        None => Err(anyhow!("Netns {name} does not exist")),
+    }
+}
+
+/// Map the network namespace IDs assigned in `netns` to namespace names, for resolving
+/// the `link_netnsid` of links listed in `netns`. Namespaces without a name are missing.
+pub async fn netns_ids(netns: impl IntoNetnsRef) -> Result<BTreeMap<usize, String>> {
+    let netns = &netns.into_netns_ref();
+    Ok(netns_list_in(netns)
+        .await?
+        .into_iter()
+        .filter_map(|item| Some((item.id?, item.name)))
+        .collect())
+}
+
 /// Add an address to an interface
-pub async fn addr_add(netns: Option<&str>, interface: &str, addr: IpNet) -> Result<()> {
+pub async fn addr_add(netns: impl IntoNetnsRef, interface: &str, addr: IpNet) -> Result<()> {
+    let netns = &netns.into_netns_ref();
     info!("addr add {:?}, {}, {}", netns, interface, addr);
-    let mut command = Command::new(IP_PATH);
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
+    let mut command = ip_command(netns);
     let success = command
         .arg("addr")
         .arg("add")
@@ -125,12 +332,10 @@ pub async fn addr_add(netns: Option<&str>, interface: &str, addr: IpNet) -> Resu
 }
 
 /// Create bridge interface.
-pub async fn bridge_add(netns: Option<&str>, interface: &str) -> Result<()> {
+pub async fn bridge_add(netns: impl IntoNetnsRef, interface: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
     info!("bridge_add({:?}, {})", netns, interface);
-    let mut command = Command::new(IP_PATH);
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
+    let mut command = ip_command(netns);
     let success = command
         .arg("link")
         .arg("add")
@@ -151,11 +356,9 @@ pub async fn bridge_add(netns: Option<&str>, interface: &str) -> Result<()> {
 }
 
 /// Check if bridge interface exists.
-pub async fn bridge_exists(netns: Option<&str>, name: &str) -> Result<bool> {
-    let mut command = Command::new(IP_PATH);
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
+pub async fn bridge_exists(netns: impl IntoNetnsRef, name: &str) -> Result<bool> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
     let output = command
         .arg("link")
         .arg("show")
@@ -164,7 +367,7 @@ pub async fn bridge_exists(netns: Option<&str>, name: &str) -> Result<bool> {
         .arg("bridge")
         .output()
         .await?;
-    if output.status.success() && output.stdout.len() > 0 {
+    if output.status.success() && !output.stdout.is_empty() {
         Ok(true)
     } else {
         Ok(false)
@@ -186,12 +389,10 @@ impl InterfaceShow {
 }
 
 /// Get details of an interface.
-pub async fn interface_show(netns: Option<&str>, interface: &str) -> Result<InterfaceShow> {
-    let mut command = Command::new(IP_PATH);
+pub async fn interface_show(netns: impl IntoNetnsRef, interface: &str) -> Result<InterfaceShow> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
     command.arg("--json");
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
     command.arg("link").arg("show").arg("dev").arg(interface);
     let output = command.output().await?;
     if !output.status.success() {
@@ -210,13 +411,166 @@ pub async fn interface_show(netns: Option<&str>, interface: &str) -> Result<Inte
     }
 }
 
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LinkCounters {
+    pub bytes: u64,
+    pub packets: u64,
+    pub errors: u64,
+    pub dropped: u64,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LinkCounterStats {
+    pub rx: LinkCounters,
+    pub tx: LinkCounters,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+struct LinkKindInfo {
+    info_kind: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct LinkStats {
+    pub ifindex: usize,
+    pub ifname: String,
+    pub operstate: String,
+    #[serde(rename = "linkinfo")]
+    linkinfo: Option<LinkKindInfo>,
+    #[serde(rename = "stats64", default)]
+    pub stats: LinkCounterStats,
+    /// Index of the peer or parent of the link, if it is in another namespace.
+    pub link_index: Option<usize>,
+    /// ID of the namespace of the peer or parent, see [`netns_ids`].
+    pub link_netnsid: Option<usize>,
+}
+
+impl LinkStats {
+    /// Kind of the link as reported by the kernel, such as `wireguard`, `veth` or `bridge`.
+    pub fn kind(&self) -> Option<&str> {
+        self.linkinfo
+            .as_ref()
+            .and_then(|info| info.info_kind.as_deref())
+    }
+}
+
+#[test]
+fn test_link_stats() {
+    let test = r#"[{"ifindex":1,"ifname":"lo","flags":["LOOPBACK","UP","LOWER_UP"],"mtu":65536,"operstate":"UNKNOWN","link_type":"loopback","stats64":{"rx":{"bytes":3938982,"packets":918,"errors":0,"dropped":0,"over_errors":0,"multicast":0},"tx":{"bytes":3938982,"packets":918,"errors":0,"dropped":0,"carrier_errors":0,"collisions":0}}},{"ifindex":7,"ifname":"wg0","flags":["POINTOPOINT","NOARP"],"mtu":1420,"operstate":"DOWN","link_type":"none","linkinfo":{"info_kind":"wireguard"},"stats64":{"rx":{"bytes":1,"packets":2,"errors":3,"dropped":4},"tx":{"bytes":5,"packets":6,"errors":7,"dropped":8}}}]"#;
+    let output: Vec<LinkStats> = serde_json::from_str(test).unwrap();
+    assert_eq!(output.len(), 2);
+    assert_eq!(output[0].kind(), None);
+    assert_eq!(output[0].stats.rx.bytes, 3938982);
+    assert_eq!(output[1].kind(), Some("wireguard"));
+    assert_eq!(
+        output[1].stats.tx,
+        LinkCounters {
+            bytes: 5,
+            packets: 6,
+            errors: 7,
+            dropped: 8
+        }
+    );
+}
+
+/// List all interfaces with their kind and traffic counters.
+pub async fn link_stats(netns: impl IntoNetnsRef) -> Result<Vec<LinkStats>> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
+    command.arg("--json").arg("--details").arg("--statistics");
+    command.arg("link").arg("show");
+    let output = command.output().await?;
+    if !output.status.success() {
+        return Err(anyhow!("Error fetching interface statistics in {netns:?}"));
+    }
+    let output = String::from_utf8(output.stdout)?;
+    let items: Vec<LinkStats> = serde_json::from_str(&output)?;
+    Ok(items)
+}
+
+/// Link whose peer is in another network namespace, such as one end of a veth pair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkPeer {
+    pub ifname: String,
+    pub peer_netnsid: usize,
+    /// Name of the namespace of the peer, if it has one.
+    pub peer_netns: Option<String>,
+    pub peer_ifindex: usize,
+    /// Name of the peer, if its namespace has a name.
+    pub peer_ifname: Option<String>,
+}
+
+impl std::fmt::Display for LinkPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} peer ", self.ifname)?;
+        match &self.peer_ifname {
+            Some(peer_ifname) => write!(f, "{peer_ifname}")?,
+            None => write!(f, "if{}", self.peer_ifindex)?,
+        }
+        match &self.peer_netns {
+            Some(peer_netns) => write!(f, " in namespace {peer_netns}"),
+            None => write!(f, " in namespace with id {}", self.peer_netnsid),
+        }
+    }
+}
+
+#[test]
+fn test_link_peer() {
+    let test = r#"[{"ifindex":4,"link_index":2,"ifname":"veth0","flags":["BROADCAST","MULTICAST","UP","LOWER_UP"],"mtu":1500,"operstate":"UP","link_type":"ether","link_netnsid":0,"linkinfo":{"info_kind":"veth"}}]"#;
+    let output: Vec<LinkStats> = serde_json::from_str(test).unwrap();
+    assert_eq!(output[0].link_index, Some(2));
+    assert_eq!(output[0].link_netnsid, Some(0));
+    let mut peer = LinkPeer {
+        ifname: "veth0".to_string(),
+        peer_netnsid: 0,
+        peer_netns: Some("tenant".to_string()),
+        peer_ifindex: 2,
+        peer_ifname: Some("eth0".to_string()),
+    };
+    assert_eq!(peer.to_string(), "veth0 peer eth0 in namespace tenant");
+    peer.peer_netns = None;
+    peer.peer_ifname = None;
+    assert_eq!(peer.to_string(), "veth0 peer if2 in namespace with id 0");
+}
+
+/// List the links of a namespace whose peers are in other namespaces, resolving the
+/// names of the peers and their namespaces.
+pub async fn link_peers(netns: impl IntoNetnsRef) -> Result<Vec<LinkPeer>> {
+    let netns = &netns.into_netns_ref();
+    let ids = netns_ids(netns).await?;
+    let mut links: BTreeMap<String, Vec<LinkStats>> = BTreeMap::new();
+    let mut peers = vec![];
+    for link in link_stats(netns).await? {
+        let (Some(peer_netnsid), Some(peer_ifindex)) = (link.link_netnsid, link.link_index) else {
+            continue;
+        };
+        let peer_netns = ids.get(&peer_netnsid).cloned();
+        let mut peer_ifname = None;
+        if let Some(peer_netns) = &peer_netns {
+            if let Entry::Vacant(entry) = links.entry(peer_netns.clone()) {
+                entry.insert(link_stats(Some(peer_netns.as_str())).await?);
+            }
+            peer_ifname = links[peer_netns]
+                .iter()
+                .find(|peer| peer.ifindex == peer_ifindex)
+                .map(|peer| peer.ifname.clone());
+        }
+        peers.push(LinkPeer {
+            ifname: link.ifname,
+            peer_netnsid,
+            peer_netns,
+            peer_ifindex,
+            peer_ifname,
+        });
+    }
+    Ok(peers)
+}
+
 /// Set an interface to be up.
-pub async fn interface_up(netns: Option<&str>, interface: &str) -> Result<()> {
+pub async fn interface_up(netns: impl IntoNetnsRef, interface: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
     info!("interface_up({:?}, {})", netns, interface);
-    let mut command = Command::new(IP_PATH);
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
+    let mut command = ip_command(netns);
     command.arg("link").arg("set").arg(interface).arg("up");
     if !command.status().await?.success() {
         return Err(anyhow!(
@@ -227,12 +581,10 @@ pub async fn interface_up(netns: Option<&str>, interface: &str) -> Result<()> {
 }
 
 /// Remove interface.
-pub async fn interface_del(netns: Option<&str>, interface: &str) -> Result<()> {
+pub async fn interface_del(netns: impl IntoNetnsRef, interface: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
     info!("interface_del({:?}, {})", netns, interface);
-    let mut command = Command::new(IP_PATH);
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
+    let mut command = ip_command(netns);
     command.arg("link").arg("del").arg("dev").arg(interface);
     if !command.status().await?.success() {
         return Err(anyhow!("Error removing interface {interface} in {netns:?}"));
@@ -241,12 +593,10 @@ pub async fn interface_del(netns: Option<&str>, interface: &str) -> Result<()> {
 }
 
 /// Sets an interface's MTU.
-pub async fn interface_mtu(netns: Option<&str>, interface: &str, mtu: usize) -> Result<()> {
+pub async fn interface_mtu(netns: impl IntoNetnsRef, interface: &str, mtu: usize) -> Result<()> {
+    let netns = &netns.into_netns_ref();
     info!("interface_mtu({:?}, {}, {})", netns, interface, mtu);
-    let mut command = Command::new(IP_PATH);
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
+    let mut command = ip_command(netns);
     command
         .arg("link")
         .arg("set")
@@ -289,12 +639,10 @@ fn test_ip_addr() {
 }
 
 /// Given an interface, list addresses.
-pub async fn addr_list(netns: Option<&str>, interface: &str) -> Result<Vec<IpNet>> {
-    let mut command = Command::new(IP_PATH);
+pub async fn addr_list(netns: impl IntoNetnsRef, interface: &str) -> Result<Vec<IpNet>> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
     command.arg("--json");
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
     let output = command
         .arg("addr")
         .arg("show")
@@ -313,27 +661,79 @@ pub async fn addr_list(netns: Option<&str>, interface: &str) -> Result<Vec<IpNet
     let items: Vec<IpInterfaceAddr> = serde_json::from_str(&output)?;
     Ok(items
         .iter()
-        .map(|addr| {
+        .flat_map(|addr| {
             addr.addr_info.iter().map(|info| match info.local {
                 IpAddr::V4(addr) => IpNet::V4(Ipv4Net::new(addr, info.prefixlen).unwrap()),
                 IpAddr::V6(addr) => IpNet::V6(Ipv6Net::new(addr, info.prefixlen).unwrap()),
             })
         })
-        .flatten()
         .collect())
 }
 
+/// Add or replace a route to a destination via an interface. When a table is given,
+/// the route is added to that routing table instead of the main one.
+pub async fn route_replace(
+    netns: impl IntoNetnsRef,
+    destination: IpNet,
+    interface: &str,
+    table: Option<&str>,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("route_replace({netns:?}, {destination}, {interface}, {table:?})");
+    let mut command = ip_command(netns);
+    command
+        .arg("route")
+        .arg("replace")
+        .arg(destination.to_string())
+        .arg("dev")
+        .arg(interface);
+    if let Some(table) = table {
+        command.arg("table").arg(table);
+    }
+    if !command.status().await?.success() {
+        return Err(anyhow!(
+            "Error adding route to {destination} via {interface} in {netns:?}"
+        ));
+    }
+    Ok(())
+}
+
+/// Remove a route to a destination via an interface.
+pub async fn route_del(
+    netns: impl IntoNetnsRef,
+    destination: IpNet,
+    interface: &str,
+    table: Option<&str>,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("route_del({netns:?}, {destination}, {interface}, {table:?})");
+    let mut command = ip_command(netns);
+    command
+        .arg("route")
+        .arg("del")
+        .arg(destination.to_string())
+        .arg("dev")
+        .arg(interface);
+    if let Some(table) = table {
+        command.arg("table").arg(table);
+    }
+    if !command.status().await?.success() {
+        return Err(anyhow!(
+            "Error removing route to {destination} via {interface} in {netns:?}"
+        ));
+    }
+    Ok(())
+}
+
 #[derive(Deserialize)]
 struct LinkInfo {
     master: Option<String>,
 }
 
-pub async fn link_get_master(netns: Option<&str>, interface: &str) -> Result<Option<String>> {
-    let mut command = Command::new(IP_PATH);
+pub async fn link_get_master(netns: impl IntoNetnsRef, interface: &str) -> Result<Option<String>> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
     command.arg("--json");
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
     let output = command
         .arg("link")
         .arg("show")
@@ -347,22 +747,24 @@ pub async fn link_get_master(netns: Option<&str>, interface: &str) -> Result<Opt
         ));
     }
     let output = String::from_utf8(output.stdout)?;
-    if output.len() == 0 {
+    if output.is_empty() {
         return Ok(None);
     }
     let output: Vec<LinkInfo> = serde_json::from_str(&output)?;
-    if output.len() == 0 {
+    if output.is_empty() {
         return Ok(None);
     }
     Ok(output[0].master.clone())
 }
 
-pub async fn link_set_master(netns: Option<&str>, interface: &str, master: &str) -> Result<()> {
-    let mut command = Command::new(IP_PATH);
+pub async fn link_set_master(
+    netns: impl IntoNetnsRef,
+    interface: &str,
+    master: &str,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
     command.arg("--json");
-    if let Some(netns) = netns {
-        command.arg("-n").arg(netns);
-    }
     let status = command
         .arg("link")
         .arg("set")
@@ -380,10 +782,13 @@ pub async fn link_set_master(netns: Option<&str>, interface: &str, master: &str)
     Ok(())
 }
 
-/// Create veth interface.
-pub async fn veth_add(netns: &str, outer: &str, inner: &str) -> Result<()> {
-    info!("veth add {}, {}, {}", netns, outer, inner);
-    if !Command::new(IP_PATH)
+/// Create veth interface. The `outer` end is created in the current namespace, the
+/// `inner` one in `netns`.
+pub async fn veth_add(netns: impl IntoNetnsRef, outer: &str, inner: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("veth add {netns:?}, {outer}, {inner}");
+    let mut command = Command::new(IP_PATH);
+    command
         .arg("link")
         .arg("add")
         .arg("dev")
@@ -391,25 +796,23 @@ pub async fn veth_add(netns: &str, outer: &str, inner: &str) -> Result<()> {
         .arg("type")
         .arg("veth")
         .arg("peer")
-        .arg(inner)
-        .arg("netns")
-        .arg(netns)
-        .status()
-        .await?
-        .success()
-    {
+        .arg(inner);
+    if let Some(netns) = netns {
+        command.arg("netns").arg(netns.link_arg());
+    }
+    if !command.status().await?.success() {
         return Err(anyhow!(
-            "Error creating veth interfaces {outer} and {inner} in {netns}",
+            "Error creating veth interfaces {outer} and {inner} in {netns:?}",
         ));
     }
     Ok(())
 }
 
 /// Check if a veth interface exists.
-pub async fn veth_exists(netns: &str, name: &str) -> Result<bool> {
-    let output = Command::new(IP_PATH)
-        .arg("-n")
-        .arg(netns)
+pub async fn veth_exists(netns: impl IntoNetnsRef, name: &str) -> Result<bool> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
+    let output = command
         .arg("link")
         .arg("show")
         .arg(name)
@@ -417,7 +820,7 @@ pub async fn veth_exists(netns: &str, name: &str) -> Result<bool> {
         .arg("veth")
         .output()
         .await?;
-    if output.status.success() && output.stdout.len() > 0 {
+    if output.status.success() && !output.stdout.is_empty() {
         Ok(true)
     } else {
         Ok(false)
@@ -425,47 +828,78 @@ pub async fn veth_exists(netns: &str, name: &str) -> Result<bool> {
 }
 
 /// Create a wireguard interface.
-pub async fn wireguard_create(netns: Option<&str>, name: &str) -> Result<()> {
-    info!("wireguard create {:?}, {}", netns, name);
-    if !Command::new(IP_PATH)
+///
+/// The interface is created in the initial network namespace and then moved into
+/// `netns`, so its UDP socket stays in the initial namespace. This allows the
+/// WireGuard interface to be the only interface of the target namespace while still
+/// being able to reach its peers. See [`wireguard_create_in`] for choosing a different
+/// namespace for the socket.
+pub async fn wireguard_create(netns: impl IntoNetnsRef, name: &str) -> Result<()> {
+    // the initial network namespace has no name, but it is the one of PID 1
+    wireguard_create_in(NetnsRef::Pid(1), netns, name).await
+}
+
+/// Create a wireguard interface in the `birthplace` network namespace and move it into
+/// `netns`. The UDP socket of a WireGuard interface always remains in the namespace it
+/// was created in, so the birthplace decides which network encrypted packets are sent
+/// and received through, while `netns` decides where the interface is used. If both
+/// refer to the same namespace, the interface is created directly in it. `None` refers
+/// to the current network namespace, use `NetnsRef::Pid(1)` for the initial one.
+pub async fn wireguard_create_in(
+    birthplace: impl IntoNetnsRef,
+    netns: impl IntoNetnsRef,
+    name: &str,
+) -> Result<()> {
+    let birthplace = &birthplace.into_netns_ref();
+    let netns = &netns.into_netns_ref();
+    info!("wireguard_create_in({birthplace:?}, {netns:?}, {name})");
+    let path = |netns: &Option<NetnsRef>| match netns {
+        Some(netns) => netns.path(),
+        None => PathBuf::from("/proc/self/ns/net"),
+    };
+    let birthplace_inode = netns_inode(&path(birthplace)).await?;
+    let same = birthplace_inode.is_some() && birthplace_inode == netns_inode(&path(netns)).await?;
+    let mut command = ip_command(birthplace);
+    command
         .arg("link")
         .arg("add")
         .arg("dev")
         .arg(name)
         .arg("type")
-        .arg("wireguard")
-        .status()
-        .await?
-        .success()
-    {
+        .arg("wireguard");
+    if !command.status().await?.success() {
         return Err(anyhow!(
-            "Error creating wireguard interface {name} for {netns:?}"
+            "Error creating wireguard interface {name} in {birthplace:?}"
         ));
     }
-    if let Some(netns) = netns {
-        if !Command::new(IP_PATH)
-            .arg("link")
-            .arg("set")
-            .arg(name)
-            .arg("netns")
-            .arg(netns)
-            .status()
-            .await?
-            .success()
-        {
-            return Err(anyhow!(
-                "Error moving wireguard interface {name} to {netns:?}"
-            ));
+    if same {
+        return Ok(());
+    }
+    // the current network namespace is the one of this process
+    let target = netns.clone().unwrap_or(NetnsRef::Pid(std::process::id()));
+    let mut command = ip_command(birthplace);
+    command
+        .arg("link")
+        .arg("set")
+        .arg(name)
+        .arg("netns")
+        .arg(target.link_arg());
+    if !command.status().await?.success() {
+        if let Err(error) = interface_del(birthplace, name).await {
+            warn!("Error removing wireguard interface {name} from {birthplace:?}: {error}");
         }
+        return Err(anyhow!(
+            "Error moving wireguard interface {name} to {netns:?}"
+        ));
     }
     Ok(())
 }
 
 /// Check if wireguard interface exists.
-pub async fn wireguard_exists(netns: &str, name: &str) -> Result<bool> {
-    let output = Command::new(IP_PATH)
-        .arg("-n")
-        .arg(netns)
+pub async fn wireguard_exists(netns: impl IntoNetnsRef, name: &str) -> Result<bool> {
+    let netns = &netns.into_netns_ref();
+    let mut command = ip_command(netns);
+    let output = command
         .arg("link")
         .arg("show")
         .arg(name)
@@ -473,21 +907,24 @@ pub async fn wireguard_exists(netns: &str, name: &str) -> Result<bool> {
         .arg("wireguard")
         .output()
         .await?;
-    if output.status.success() && output.stdout.len() > 0 {
+    if output.status.success() && !output.stdout.is_empty() {
         Ok(true)
     } else {
         Ok(false)
     }
 }
 
-/// Sync the configuration state of a WireGuard interface with a given one.
+/// Sync the configuration state of a WireGuard interface with a given one. The
+/// configuration is read from `/etc/netns/<netns>/wireguard/<name>.conf`, which is only
+/// visible with `ip netns exec`, so the namespace needs a name. Use [`netns_attach`] to
+/// name the namespace of a process.
 pub async fn wireguard_syncconf(netns: &str, name: &str) -> Result<()> {
     info!("wireguard syncconf {}, {}", netns, name);
     if !Command::new(IP_PATH)
         .arg("netns")
         .arg("exec")
         .arg(netns)
-        .arg("wg")
+        .arg(WG_PATH)
         .arg("syncconf")
         .arg(name)
         .arg(format!("/etc/wireguard/{}.conf", name))
@@ -500,12 +937,9 @@ pub async fn wireguard_syncconf(netns: &str, name: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn wireguard_stats(netns: &str, name: &str) -> Result<NetworkStats> {
-    let result = Command::new(IP_PATH)
-        .arg("netns")
-        .arg("exec")
-        .arg(netns)
-        .arg("wg")
+pub async fn wireguard_stats(netns: impl IntoNetnsRef, name: &str) -> Result<NetworkStats> {
+    let netns = &netns.into_netns_ref();
+    let result = netns_command(netns, WG_PATH)
         .arg("show")
         .arg(name)
         .arg("dump")
@@ -519,59 +953,256 @@ pub async fn wireguard_stats(netns: &str, name: &str) -> Result<NetworkStats> {
     Ok(stats)
 }
 
-pub async fn iptables_save(netns: Option<&str>) -> Result<String> {
-    let mut command = if let Some(netns) = netns {
-        let mut command = Command::new(IP_PATH);
-        command
-            .arg("netns")
-            .arg("exec")
-            .arg(netns)
-            .arg(IPTABLES_SAVE_PATH);
-        command
-    } else {
-        Command::new(IPTABLES_SAVE_PATH)
+/// Set the endpoint of a peer of a WireGuard interface.
+pub async fn wireguard_peer_endpoint(
+    netns: &str,
+    name: &str,
+    peer: &Pubkey,
+    endpoint: SocketAddr,
+) -> Result<()> {
+    info!("wireguard_peer_endpoint({netns}, {name}, {peer}, {endpoint})");
+    if !Command::new(IP_PATH)
+        .arg("netns")
+        .arg("exec")
+        .arg(netns)
+        .arg(WG_PATH)
+        .arg("set")
+        .arg(name)
+        .arg("peer")
+        .arg(peer.to_string())
+        .arg("endpoint")
+        .arg(endpoint.to_string())
+        .status()
+        .await?
+        .success()
+    {
+        return Err(anyhow!(
+            "Error setting endpoint of peer {peer} on {name} in {netns}"
+        ));
+    }
+    Ok(())
+}
+
+/// Get statistics of all WireGuard interfaces in a network namespace, by interface name.
+pub async fn wireguard_stats_all(
+    netns: impl IntoNetnsRef,
+) -> Result<BTreeMap<String, NetworkStats>> {
+    let netns = &netns.into_netns_ref();
+    let result = netns_command(netns, WG_PATH)
+        .arg("show")
+        .arg("all")
+        .arg("dump")
+        .output()
+        .await?;
+    if !result.status.success() {
+        return Err(anyhow!("Error fetching wireguard stats in {netns:?}"));
+    }
+    let result = String::from_utf8(result.stdout)?;
+    NetworkStats::parse_all(&result)
+}
+
+/// Generate a new WireGuard private key, equivalent to `wg genkey`.
+pub fn wireguard_genkey() -> Privkey {
+    Privkey::generate()
+}
+
+/// Derive the public key for a WireGuard private key, equivalent to `wg pubkey`.
+pub fn wireguard_pubkey(key: &Privkey) -> Pubkey {
+    key.pubkey()
+}
+
+/// Generate a new WireGuard preshared key, equivalent to `wg genpsk`.
+pub fn wireguard_genpsk() -> Secret {
+    Secret::generate()
+}
+
+#[test]
+fn test_wireguard_keys() {
+    let key = wireguard_genkey();
+    assert!(key.valid());
+    assert_eq!(wireguard_pubkey(&key), key.pubkey());
+    assert_ne!(wireguard_genkey(), key);
+    assert_ne!(wireguard_genpsk(), wireguard_genpsk());
+}
+
+/// Path of the key store for a WireGuard interface's private key. This lives in the
+/// network namespace config folder, which `ip netns exec` mounts as `/etc/wireguard`.
+pub fn wireguard_key_path(netns: &str, name: &str) -> PathBuf {
+    let mut path = PathBuf::from("/etc/netns");
+    path.push(netns);
+    path.push("wireguard");
+    path.push(format!("{name}.key"));
+    path
+}
+
+/// Store the private key of a WireGuard interface on disk.
+pub async fn wireguard_key_save(netns: &str, name: &str, key: &Privkey) -> Result<()> {
+    info!("wireguard_key_save({netns}, {name})");
+    let filename = PathBuf::from(format!("wireguard/{name}.key"));
+    netns_write_file_secret(netns, &filename, &format!("{key}\n"))
+        .await
+        .context("Writing wireguard key file")
+}
+
+/// Load the stored private key of a WireGuard interface, if there is one.
+pub async fn wireguard_key_load(netns: &str, name: &str) -> Result<Option<Privkey>> {
+    let path = wireguard_key_path(netns, name);
+    let data = match tokio::fs::read_to_string(&path).await {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error).context("Reading wireguard key file"),
     };
-    let output = command.output().await?;
+    let key = Privkey::from_str(data.trim()).context("Parsing wireguard key file")?;
+    Ok(Some(key))
+}
+
+/// Remove the stored private key of a WireGuard interface.
+pub async fn wireguard_key_delete(netns: &str, name: &str) -> Result<()> {
+    info!("wireguard_key_delete({netns}, {name})");
+    tokio::fs::remove_file(wireguard_key_path(netns, name))
+        .await
+        .context("Removing wireguard key file")?;
+    Ok(())
+}
+
+/// Rotate the private key of a WireGuard interface. Generates a fresh key, swaps it
+/// into the running interface in a single `wg set` call and stores it in the key store
+/// once that succeeded, so a failed rotation leaves the stored key intact. Returns the
+/// new public key, which needs to be announced to the peers.
+///
+/// If the interface is managed with [`wireguard_syncconf`], its configuration file
+/// needs to be updated with the new key as well, otherwise the next sync reverts it.
+pub async fn wireguard_rotate_key(netns: &str, name: &str) -> Result<Pubkey> {
+    info!("wireguard_rotate_key({netns}, {name})");
+    let key = wireguard_genkey();
+    let mut handle = Command::new(IP_PATH)
+        .arg("netns")
+        .arg("exec")
+        .arg(netns)
+        .arg(WG_PATH)
+        .arg("set")
+        .arg(name)
+        .arg("private-key")
+        .arg("/dev/stdin")
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = handle.stdin.take().unwrap();
+    stdin.write_all(format!("{key}\n").as_bytes()).await?;
+    drop(stdin);
+    if !handle.wait().await?.success() {
+        return Err(anyhow!(
+            "Error setting private key of wireguard interface {name} in {netns}"
+        ));
+    }
+    wireguard_key_save(netns, name, &key).await?;
+    Ok(wireguard_pubkey(&key))
+}
+
+/// Build a command which runs `program` inside a network namespace, or in the current
+/// one if no namespace is given. Named namespaces are entered with `ip netns exec`, so
+/// their files from `/etc/netns` are used, others with `nsenter`.
+fn netns_command(netns: &Option<NetnsRef>, program: &str) -> Command {
+    Command::from(netns_command_blocking(netns, program))
+}
+
+fn netns_command_blocking(netns: &Option<NetnsRef>, program: &str) -> std::process::Command {
+    match netns {
+        Some(NetnsRef::Named(netns)) => {
+            let mut command = std::process::Command::new(IP_PATH);
+            command.arg("netns").arg("exec").arg(netns).arg(program);
+            command
+        }
+        Some(netns) => {
+            let mut command = std::process::Command::new(NSENTER_PATH);
+            command
+                .arg(format!("--net={}", netns.path().display()))
+                .arg(program);
+            command
+        }
+        None => std::process::Command::new(program),
+    }
+}
+
+/// Build an `ip` command operating on a network namespace.
+fn ip_command(netns: &Option<NetnsRef>) -> Command {
+    Command::from(ip_command_blocking(netns))
+}
+
+fn ip_command_blocking(netns: &Option<NetnsRef>) -> std::process::Command {
+    match netns {
+        Some(NetnsRef::Named(netns)) => {
+            let mut command = std::process::Command::new(IP_PATH);
+            command.arg("-n").arg(netns);
+            command
+        }
+        netns => netns_command_blocking(netns, IP_PATH),
+    }
+}
+
+async fn xtables_save(netns: &Option<NetnsRef>, program: &str) -> Result<String> {
+    let output = netns_command(netns, program).output().await?;
     if !output.status.success() {
-        return Err(anyhow!("Error saving iptables state"));
+        return Err(anyhow!("Error saving {program} state in {netns:?}"));
     }
     let state = String::from_utf8(output.stdout)?;
     Ok(state)
 }
 
-pub async fn iptables_restore(netns: Option<&str>, state: &str) -> Result<()> {
-    info!("iptables_restore({:?}, {})", netns, state.len());
-    let mut command = if let Some(netns) = netns {
-        let mut command = Command::new(IP_PATH);
-        command
-            .arg("netns")
-            .arg("exec")
-            .arg(netns)
-            .arg(IPTABLES_RESTORE_PATH)
-            .arg("-w");
-        command
-    } else {
-        Command::new(IPTABLES_RESTORE_PATH)
-    };
+async fn xtables_restore(
+    netns: &Option<NetnsRef>,
+    program: &str,
+    noflush: bool,
+    state: &str,
+) -> Result<()> {
+    let mut command = netns_command(netns, program);
+    command.arg("-w");
+    if noflush {
+        command.arg("--noflush");
+    }
     let mut handle = command.stdin(std::process::Stdio::piped()).spawn()?;
     let mut stdin = handle.stdin.take().unwrap();
     stdin.write_all(state.as_bytes()).await?;
     drop(stdin);
     let result = handle.wait().await?;
     if !result.success() {
-        return Err(anyhow!("Error restoring iptables state"));
+        return Err(anyhow!("Error restoring {program} state in {netns:?}"));
     }
     Ok(())
 }
 
-pub async fn nginx_reload() -> Result<()> {
-    let status = Command::new("nginx")
-        .arg("-s")
-        .arg("reload")
-        .status()
-        .await?;
-    if !status.success() {
-        return Err(anyhow!("Error reloading nginx"));
-    }
-    Ok(())
+pub async fn iptables_save(netns: impl IntoNetnsRef) -> Result<String> {
+    let netns = &netns.into_netns_ref();
+    xtables_save(netns, IPTABLES_SAVE_PATH).await
+}
+
+pub async fn iptables_restore(netns: impl IntoNetnsRef, state: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("iptables_restore({:?}, {})", netns, state.len());
+    xtables_restore(netns, IPTABLES_RESTORE_PATH, false, state).await
+}
+
+/// Apply iptables-restore input without flushing the tables it mentions, leaving rules
+/// that are not part of `state` in place.
+pub async fn iptables_restore_noflush(netns: impl IntoNetnsRef, state: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("iptables_restore_noflush({:?}, {})", netns, state.len());
+    xtables_restore(netns, IPTABLES_RESTORE_PATH, true, state).await
+}
+
+pub async fn ip6tables_save(netns: impl IntoNetnsRef) -> Result<String> {
+    let netns = &netns.into_netns_ref();
+    xtables_save(netns, IP6TABLES_SAVE_PATH).await
+}
+
+pub async fn ip6tables_restore(netns: impl IntoNetnsRef, state: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("ip6tables_restore({:?}, {})", netns, state.len());
+    xtables_restore(netns, IP6TABLES_RESTORE_PATH, false, state).await
+}
+
+/// Apply ip6tables-restore input without flushing the tables it mentions.
+pub async fn ip6tables_restore_noflush(netns: impl IntoNetnsRef, state: &str) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("ip6tables_restore_noflush({:?}, {})", netns, state.len());
+    xtables_restore(netns, IP6TABLES_RESTORE_PATH, true, state).await
 }
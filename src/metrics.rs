@@ -0,0 +1,272 @@
+use crate::{link_stats, netns_list, wireguard_stats_all, LinkStats, NetworkStats};
+use anyhow::Result;
+use log::*;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Statistics collected from a single network namespace.
+#[derive(Clone, Debug)]
+pub struct NetnsMetrics {
+    pub netns: String,
+    pub links: Vec<LinkStats>,
+    pub wireguard: Vec<(String, NetworkStats)>,
+}
+
+/// Collect interface and WireGuard statistics of a network namespace.
+pub async fn metrics_collect(netns: &str) -> Result<NetnsMetrics> {
+    let links = link_stats(Some(netns)).await?;
+    let wireguard = if links.iter().any(|link| link.kind() == Some("wireguard")) {
+        wireguard_stats_all(netns).await?.into_iter().collect()
+    } else {
+        vec![]
+    };
+    Ok(NetnsMetrics {
+        netns: netns.to_string(),
+        links,
+        wireguard,
+    })
+}
+
+/// Collect statistics for every network namespace and render them in the Prometheus
+/// text exposition format, ready to be served as the body of a scrape response.
+/// Namespaces which cannot be read, for example because they were deleted while
+/// collecting, are logged and left out.
+pub async fn metrics_render() -> Result<String> {
+    let mut metrics = vec![];
+    for netns in netns_list().await?.iter().filter(|netns| netns.alive) {
+        match metrics_collect(&netns.name).await {
+            Ok(netns) => metrics.push(netns),
+            Err(error) => warn!("Error collecting metrics of {}: {error}", netns.name),
+        }
+    }
+    Ok(metrics_format(&metrics, SystemTime::now()))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+struct MetricFamily {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl MetricFamily {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        MetricFamily {
+            name,
+            help,
+            kind,
+            samples: vec![],
+        }
+    }
+
+    fn sample(&mut self, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+            .collect();
+        self.samples.push((labels.join(","), value));
+    }
+
+    fn write(&self, output: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+        writeln!(output, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(output, "# TYPE {} {}", self.name, self.kind).unwrap();
+        for (labels, value) in &self.samples {
+            writeln!(output, "{}{{{labels}}} {value}", self.name).unwrap();
+        }
+    }
+}
+
+/// Render collected statistics in the Prometheus text exposition format. Interface
+/// samples are labelled with `netns` and `interface`, WireGuard peer samples
+/// additionally with `peer`. Handshake ages are computed relative to `now`.
+pub fn metrics_format(metrics: &[NetnsMetrics], now: SystemTime) -> String {
+    let mut rx_bytes = MetricFamily::new(
+        "interface_receive_bytes_total",
+        "counter",
+        "Bytes received by the interface.",
+    );
+    let mut tx_bytes = MetricFamily::new(
+        "interface_transmit_bytes_total",
+        "counter",
+        "Bytes transmitted by the interface.",
+    );
+    let mut rx_packets = MetricFamily::new(
+        "interface_receive_packets_total",
+        "counter",
+        "Packets received by the interface.",
+    );
+    let mut tx_packets = MetricFamily::new(
+        "interface_transmit_packets_total",
+        "counter",
+        "Packets transmitted by the interface.",
+    );
+    let mut rx_errors = MetricFamily::new(
+        "interface_receive_errors_total",
+        "counter",
+        "Receive errors of the interface.",
+    );
+    let mut tx_errors = MetricFamily::new(
+        "interface_transmit_errors_total",
+        "counter",
+        "Transmit errors of the interface.",
+    );
+    let mut rx_dropped = MetricFamily::new(
+        "interface_receive_dropped_total",
+        "counter",
+        "Received packets dropped by the interface.",
+    );
+    let mut tx_dropped = MetricFamily::new(
+        "interface_transmit_dropped_total",
+        "counter",
+        "Transmitted packets dropped by the interface.",
+    );
+    let mut peer_rx = MetricFamily::new(
+        "wireguard_peer_receive_bytes_total",
+        "counter",
+        "Bytes received from the WireGuard peer.",
+    );
+    let mut peer_tx = MetricFamily::new(
+        "wireguard_peer_transmit_bytes_total",
+        "counter",
+        "Bytes transmitted to the WireGuard peer.",
+    );
+    let mut peer_handshake = MetricFamily::new(
+        "wireguard_peer_latest_handshake_seconds",
+        "gauge",
+        "UNIX timestamp of the latest handshake with the WireGuard peer.",
+    );
+    let mut peer_handshake_age = MetricFamily::new(
+        "wireguard_peer_handshake_age_seconds",
+        "gauge",
+        "Seconds since the latest handshake with the WireGuard peer.",
+    );
+
+    for netns in metrics {
+        for link in &netns.links {
+            let labels = [("netns", netns.netns.as_str()), ("interface", &link.ifname)];
+            let stats = &link.stats;
+            rx_bytes.sample(&labels, stats.rx.bytes as f64);
+            tx_bytes.sample(&labels, stats.tx.bytes as f64);
+            rx_packets.sample(&labels, stats.rx.packets as f64);
+            tx_packets.sample(&labels, stats.tx.packets as f64);
+            rx_errors.sample(&labels, stats.rx.errors as f64);
+            tx_errors.sample(&labels, stats.tx.errors as f64);
+            rx_dropped.sample(&labels, stats.rx.dropped as f64);
+            tx_dropped.sample(&labels, stats.tx.dropped as f64);
+        }
+        for (interface, stats) in &netns.wireguard {
+            for peer in stats.peers() {
+                let key = peer.public_key.to_string();
+                let labels = [
+                    ("netns", netns.netns.as_str()),
+                    ("interface", interface.as_str()),
+                    ("peer", key.as_str()),
+                ];
+                peer_rx.sample(&labels, peer.transfer_rx as f64);
+                peer_tx.sample(&labels, peer.transfer_tx as f64);
+                if let Some(handshake) = peer.latest_handshake {
+                    let timestamp = handshake
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs_f64();
+                    let age = now
+                        .duration_since(handshake)
+                        .unwrap_or_default()
+                        .as_secs_f64();
+                    peer_handshake.sample(&labels, timestamp);
+                    peer_handshake_age.sample(&labels, age);
+                }
+            }
+        }
+    }
+
+    let mut output = String::new();
+    for family in [
+        rx_bytes,
+        tx_bytes,
+        rx_packets,
+        tx_packets,
+        rx_errors,
+        tx_errors,
+        rx_dropped,
+        tx_dropped,
+        peer_rx,
+        peer_tx,
+        peer_handshake,
+        peer_handshake_age,
+    ] {
+        family.write(&mut output);
+    }
+    output
+}
+
+#[test]
+fn test_metrics_format() {
+    use std::str::FromStr;
+    use std::time::Duration;
+    let links: Vec<LinkStats> = serde_json::from_str(
+        r#"[{"ifindex":7,"ifname":"wg\"0","operstate":"UNKNOWN","linkinfo":{"info_kind":"wireguard"},"stats64":{"rx":{"bytes":1,"packets":2,"errors":3,"dropped":4},"tx":{"bytes":5,"packets":6,"errors":7,"dropped":8}}}]"#,
+    )
+    .unwrap();
+    let stats = NetworkStats::from_str(
+        "cFnKhp0uB+UMUgERCZEpMdjDmD5c+Ltw4YyoZchIf1w=\tTxsQfcl4+RSI0RXMvIa1h7/HoeKGiw2EPSa9xjfy8ww=\t51820\toff\n\
+         AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A=\t(none)\t1.2.3.4:51820\t10.0.0.2/32\t1000\t100\t200\t25",
+    )
+    .unwrap();
+    let metrics = vec![NetnsMetrics {
+        netns: "tenant".to_string(),
+        links,
+        wireguard: vec![("wg\"0".to_string(), stats)],
+    }];
+    let output = metrics_format(&metrics, UNIX_EPOCH + Duration::from_secs(1060));
+    assert_eq!(
+        output,
+        r#"# HELP interface_receive_bytes_total Bytes received by the interface.
+# TYPE interface_receive_bytes_total counter
+interface_receive_bytes_total{netns="tenant",interface="wg\"0"} 1
+# HELP interface_transmit_bytes_total Bytes transmitted by the interface.
+# TYPE interface_transmit_bytes_total counter
+interface_transmit_bytes_total{netns="tenant",interface="wg\"0"} 5
+# HELP interface_receive_packets_total Packets received by the interface.
+# TYPE interface_receive_packets_total counter
+interface_receive_packets_total{netns="tenant",interface="wg\"0"} 2
+# HELP interface_transmit_packets_total Packets transmitted by the interface.
+# TYPE interface_transmit_packets_total counter
+interface_transmit_packets_total{netns="tenant",interface="wg\"0"} 6
+# HELP interface_receive_errors_total Receive errors of the interface.
+# TYPE interface_receive_errors_total counter
+interface_receive_errors_total{netns="tenant",interface="wg\"0"} 3
+# HELP interface_transmit_errors_total Transmit errors of the interface.
+# TYPE interface_transmit_errors_total counter
+interface_transmit_errors_total{netns="tenant",interface="wg\"0"} 7
+# HELP interface_receive_dropped_total Received packets dropped by the interface.
+# TYPE interface_receive_dropped_total counter
+interface_receive_dropped_total{netns="tenant",interface="wg\"0"} 4
+# HELP interface_transmit_dropped_total Transmitted packets dropped by the interface.
+# TYPE interface_transmit_dropped_total counter
+interface_transmit_dropped_total{netns="tenant",interface="wg\"0"} 8
+# HELP wireguard_peer_receive_bytes_total Bytes received from the WireGuard peer.
+# TYPE wireguard_peer_receive_bytes_total counter
+wireguard_peer_receive_bytes_total{netns="tenant",interface="wg\"0",peer="AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A="} 100
+# HELP wireguard_peer_transmit_bytes_total Bytes transmitted to the WireGuard peer.
+# TYPE wireguard_peer_transmit_bytes_total counter
+wireguard_peer_transmit_bytes_total{netns="tenant",interface="wg\"0",peer="AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A="} 200
+# HELP wireguard_peer_latest_handshake_seconds UNIX timestamp of the latest handshake with the WireGuard peer.
+# TYPE wireguard_peer_latest_handshake_seconds gauge
+wireguard_peer_latest_handshake_seconds{netns="tenant",interface="wg\"0",peer="AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A="} 1000
+# HELP wireguard_peer_handshake_age_seconds Seconds since the latest handshake with the WireGuard peer.
+# TYPE wireguard_peer_handshake_age_seconds gauge
+wireguard_peer_handshake_age_seconds{netns="tenant",interface="wg\"0",peer="AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A="} 60
+"#
+    );
+}
@@ -0,0 +1,525 @@
+use crate::{ip_command, link_stats, IntoNetnsRef, LinkStats, IP_PATH};
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use log::*;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+
+/// Number of times [`monitor`] checks whether a monitoring process has subscribed.
+const MONITOR_START_ATTEMPTS: usize = 100;
+
+/// Interval at which [`monitor`] checks whether a monitoring process has subscribed.
+const MONITOR_START_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Kind of objects to watch with [`monitor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MonitorKind {
+    Link,
+    Address,
+    Route,
+    /// Named network namespaces, these are not specific to a namespace.
+    Netns,
+}
+
+impl MonitorKind {
+    /// Object name for `ip monitor`.
+    fn object(&self) -> Option<&'static str> {
+        match self {
+            MonitorKind::Link => Some("link"),
+            MonitorKind::Address => Some("address"),
+            MonitorKind::Route => Some("route"),
+            MonitorKind::Netns => None,
+        }
+    }
+}
+
+/// Change reported by [`monitor`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetworkEvent {
+    /// Link was created.
+    LinkAdded {
+        ifindex: usize,
+        ifname: String,
+    },
+    /// Flags or state of a link changed, such as it being set up.
+    LinkChanged {
+        ifindex: usize,
+        ifname: String,
+        operstate: String,
+    },
+    /// Link was removed.
+    LinkRemoved {
+        ifindex: usize,
+        ifname: String,
+    },
+    AddrAdded {
+        ifname: String,
+        addr: IpNet,
+    },
+    AddrRemoved {
+        ifname: String,
+        addr: IpNet,
+    },
+    /// Route was added or replaced, or removed if `removed` is set. The destination is
+    /// as printed by `ip route`, such as `default` or `10.0.0.0/8`.
+    RouteChanged {
+        destination: String,
+        dev: Option<String>,
+        table: Option<String>,
+        removed: bool,
+    },
+    NetnsAdded {
+        name: String,
+    },
+    NetnsRemoved {
+        name: String,
+    },
+}
+
+/// Route types `ip route` prints in front of the destination.
+const ROUTE_TYPES: &[&str] = &[
+    "unicast",
+    "local",
+    "broadcast",
+    "multicast",
+    "anycast",
+    "blackhole",
+    "unreachable",
+    "prohibit",
+    "throw",
+    "nat",
+];
+
+/// Parses the output of `ip -oneline monitor label`. Links which are already known
+/// are reported as changed rather than added.
+#[derive(Clone, Debug, Default)]
+struct MonitorParser {
+    links: BTreeSet<usize>,
+}
+
+impl MonitorParser {
+    fn parse(&mut self, line: &str) -> Option<NetworkEvent> {
+        let (label, message) = line.strip_prefix('[')?.split_once(']')?;
+        let (removed, message) = match message.strip_prefix("Deleted ") {
+            Some(message) => (true, message),
+            None => (false, message),
+        };
+        let mut tokens = message.split_whitespace();
+        match label {
+            "LINK" => {
+                let ifindex = tokens.next()?.strip_suffix(':')?.parse().ok()?;
+                let ifname = tokens.next()?.strip_suffix(':')?;
+                // peers of veth interfaces are printed as `name@peer`
+                let ifname = ifname.split('@').next()?.to_string();
+                if removed {
+                    self.links.remove(&ifindex);
+                    return Some(NetworkEvent::LinkRemoved { ifindex, ifname });
+                }
+                if self.links.insert(ifindex) {
+                    return Some(NetworkEvent::LinkAdded { ifindex, ifname });
+                }
+                let operstate = tokens
+                    .skip_while(|token| *token != "state")
+                    .nth(1)?
+                    .to_string();
+                Some(NetworkEvent::LinkChanged {
+                    ifindex,
+                    ifname,
+                    operstate,
+                })
+            }
+            "ADDR" => {
+                let ifname = tokens.nth(1)?.to_string();
+                let addr = tokens.nth(1)?;
+                // point-to-point addresses are printed without prefix length
+                let addr = match addr.parse() {
+                    Ok(addr) => addr,
+                    Err(_) => IpNet::from(addr.parse::<IpAddr>().ok()?),
+                };
+                Some(match removed {
+                    false => NetworkEvent::AddrAdded { ifname, addr },
+                    true => NetworkEvent::AddrRemoved { ifname, addr },
+                })
+            }
+            "ROUTE" => {
+                let tokens: Vec<&str> = tokens.collect();
+                let tokens = match tokens.first() {
+                    Some(first) if ROUTE_TYPES.contains(first) => &tokens[1..],
+                    _ => &tokens[..],
+                };
+                let value = |key: &str| {
+                    tokens
+                        .windows(2)
+                        .find(|pair| pair[0] == key)
+                        .map(|pair| pair[1].to_string())
+                };
+                Some(NetworkEvent::RouteChanged {
+                    destination: tokens.first()?.to_string(),
+                    dev: value("dev"),
+                    table: value("table"),
+                    removed,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Events for the links that were added or removed between two listings.
+fn links_diff(before: &[LinkStats], after: &[LinkStats]) -> Vec<NetworkEvent> {
+    let removed = before
+        .iter()
+        .filter(|link| !after.iter().any(|after| after.ifindex == link.ifindex))
+        .map(|link| NetworkEvent::LinkRemoved {
+            ifindex: link.ifindex,
+            ifname: link.ifname.clone(),
+        });
+    let added = after
+        .iter()
+        .filter(|link| !before.iter().any(|before| before.ifindex == link.ifindex))
+        .map(|link| NetworkEvent::LinkAdded {
+            ifindex: link.ifindex,
+            ifname: link.ifname.clone(),
+        });
+    removed.chain(added).collect()
+}
+
+/// Parse the output of `ip netns monitor`.
+fn netns_monitor_parse(line: &str) -> Option<NetworkEvent> {
+    match line.split_once(' ')? {
+        ("add", name) => Some(NetworkEvent::NetnsAdded {
+            name: name.to_string(),
+        }),
+        ("delete", name) => Some(NetworkEvent::NetnsRemoved {
+            name: name.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// Stream of [`NetworkEvent`]s returned by [`monitor`]. The monitoring processes are
+/// killed when it is dropped.
+#[derive(Debug)]
+pub struct NetworkMonitor {
+    events: ReceiverStream<Result<NetworkEvent>>,
+    _children: Vec<Child>,
+}
+
+impl Stream for NetworkMonitor {
+    type Item = Result<NetworkEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+/// Spawn a monitoring process, returning it and its output.
+fn monitor_spawn(mut command: Command) -> Result<(Child, ChildStdout)> {
+    let mut child = command.stdout(Stdio::piped()).kill_on_drop(true).spawn()?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("Missing output of monitoring process"))?;
+    Ok((child, stdout))
+}
+
+/// Wait until a monitoring process has subscribed to changes, as reported by
+/// `subscribed` for its PID, so that no change is missed once [`monitor`] returns.
+async fn monitor_wait<F: Future<Output = bool>>(
+    child: &mut Child,
+    subscribed: impl Fn(u32) -> F,
+) -> Result<()> {
+    let pid = child
+        .id()
+        .ok_or_else(|| anyhow!("Monitoring process exited"))?;
+    for _ in 0..MONITOR_START_ATTEMPTS {
+        if subscribed(pid).await {
+            return Ok(());
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!("Monitoring process exited with {status}"));
+        }
+        tokio::time::sleep(MONITOR_START_INTERVAL).await;
+    }
+    Err(anyhow!(
+        "Timeout waiting for monitoring process {pid} to start"
+    ))
+}
+
+/// Inodes of the sockets a process has open.
+async fn process_sockets(pid: u32) -> Vec<u64> {
+    let mut sockets = vec![];
+    let mut entries = match tokio::fs::read_dir(format!("/proc/{pid}/fd")).await {
+        Ok(entries) => entries,
+        Err(_) => return sockets,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if let Ok(target) = tokio::fs::read_link(entry.path()).await {
+            let target = target.to_string_lossy();
+            if let Some(inode) = target
+                .strip_prefix("socket:[")
+                .and_then(|inode| inode.strip_suffix(']'))
+                .and_then(|inode| inode.parse().ok())
+            {
+                sockets.push(inode);
+            }
+        }
+    }
+    sockets
+}
+
+/// Check if a process has a routing netlink socket bound to multicast groups, which
+/// `ip monitor` opens once it listens for changes.
+async fn netlink_subscribed(pid: u32) -> bool {
+    let sockets = process_sockets(pid).await;
+    match tokio::fs::read_to_string(format!("/proc/{pid}/net/netlink")).await {
+        Ok(table) => netlink_subscribed_in(&table, &sockets),
+        Err(_) => false,
+    }
+}
+
+/// Check the netlink socket table of `/proc/net/netlink` for a routing socket with
+/// multicast groups among `sockets`.
+fn netlink_subscribed_in(table: &str, sockets: &[u64]) -> bool {
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // protocol 0 is NETLINK_ROUTE, the groups are a hexadecimal bitmap
+        fields.len() >= 10
+            && fields[1] == "0"
+            && fields[3].bytes().any(|digit| digit != b'0')
+            && fields[9]
+                .parse()
+                .is_ok_and(|inode| sockets.contains(&inode))
+    })
+}
+
+/// Check if a process has an inotify watch, which `ip netns monitor` adds once it
+/// listens for changes.
+async fn inotify_subscribed(pid: u32) -> bool {
+    let mut entries = match tokio::fs::read_dir(format!("/proc/{pid}/fdinfo")).await {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if let Ok(info) = tokio::fs::read_to_string(entry.path()).await {
+            if info.lines().any(|line| line.starts_with("inotify wd:")) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Forward the events parsed from the output of a monitoring process, after the
+/// `initial` ones.
+fn monitor_forward(
+    stdout: ChildStdout,
+    sender: mpsc::Sender<Result<NetworkEvent>>,
+    initial: Vec<NetworkEvent>,
+    mut parse: impl FnMut(&str) -> Option<NetworkEvent> + Send + 'static,
+) {
+    tokio::spawn(async move {
+        for event in initial {
+            if sender.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+        let mut lines = BufReader::new(stdout).lines();
+        loop {
+            let event = match lines.next_line().await {
+                Ok(Some(line)) => match parse(&line) {
+                    Some(event) => Ok(event),
+                    None => {
+                        debug!("Ignoring monitor output {line:?}");
+                        continue;
+                    }
+                },
+                Ok(None) => return,
+                Err(error) => Err(error.into()),
+            };
+            let failed = event.is_err();
+            if sender.send(event).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+}
+
+/// Watch a network namespace for changes of the given kinds of objects, as reported by
+/// `ip monitor` and `ip netns monitor`. This allows reacting to changes made by others,
+/// such as a bridge being removed by hand, without polling.
+///
+/// Returns once the monitoring processes have subscribed, so every change made after
+/// that is reported. Links added or removed while subscribing are reported as well.
+///
+/// The plain output of `ip monitor` is parsed, since older versions of iproute2 ignore
+/// `-json` for it. The stream ends if the monitoring processes exit.
+pub async fn monitor(netns: impl IntoNetnsRef, kinds: &[MonitorKind]) -> Result<NetworkMonitor> {
+    let netns = &netns.into_netns_ref();
+    info!("monitor({netns:?}, {kinds:?})");
+    let (sender, receiver) = mpsc::channel(64);
+    let mut children = vec![];
+    let objects: Vec<&str> = kinds.iter().filter_map(MonitorKind::object).collect();
+    if !objects.is_empty() {
+        let links = kinds.contains(&MonitorKind::Link);
+        let before = match links {
+            true => link_stats(netns).await?,
+            false => vec![],
+        };
+        let mut command = ip_command(netns);
+        command
+            .arg("-oneline")
+            .arg("monitor")
+            .arg("label")
+            .args(&objects);
+        let (mut child, stdout) = monitor_spawn(command)?;
+        monitor_wait(&mut child, netlink_subscribed).await?;
+        children.push(child);
+        // links listed after subscribing are either known or reported as added later,
+        // changes since the first listing may have been missed and are reported now
+        let mut parser = MonitorParser::default();
+        let mut initial = vec![];
+        if links {
+            let after = link_stats(netns).await?;
+            initial = links_diff(&before, &after);
+            parser.links = after.iter().map(|link| link.ifindex).collect();
+        }
+        monitor_forward(stdout, sender.clone(), initial, move |line| {
+            parser.parse(line)
+        });
+    }
+    if kinds.contains(&MonitorKind::Netns) {
+        let mut command = Command::new(IP_PATH);
+        command.arg("netns").arg("monitor");
+        let (mut child, stdout) = monitor_spawn(command)?;
+        monitor_wait(&mut child, inotify_subscribed).await?;
+        children.push(child);
+        monitor_forward(stdout, sender, vec![], netns_monitor_parse);
+    }
+    Ok(NetworkMonitor {
+        events: ReceiverStream::new(receiver),
+        _children: children,
+    })
+}
+
+#[test]
+fn test_monitor_parse() {
+    let mut parser = MonitorParser::default();
+    let events: Vec<NetworkEvent> = [
+        "[LINK]2: br9: <BROADCAST,MULTICAST> mtu 1500 qdisc noop state DOWN group default \\    link/ether fe:26:a9:e0:a5:11 brd ff:ff:ff:ff:ff:ff",
+        "[LINK]2: br9: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UNKNOWN group default \\    link/ether fe:26:a9:e0:a5:11 brd ff:ff:ff:ff:ff:ff",
+        "[ADDR]1: lo    inet 10.1.2.3/24 scope global lo\\       valid_lft forever preferred_lft forever",
+        "[ADDR]Deleted 3: wg0    inet 10.0.0.1 peer 10.0.0.2/32 scope global wg0\\       valid_lft forever preferred_lft forever",
+        "[ROUTE]10.9.0.0/16 dev lo table 100 scope link ",
+        "[ROUTE]Deleted local 10.1.2.3 dev lo table local proto kernel scope host src 10.1.2.3 ",
+        "[ROUTE]default via 192.168.1.1 dev eth0 proto dhcp metric 100 ",
+        "[LINK]Deleted 4: veth0@if5: <BROADCAST,MULTICAST> mtu 1500 qdisc noop state DOWN group default \\    link/ether 7e:0f:ce:ef:e1:12 brd ff:ff:ff:ff:ff:ff link-netnsid 0",
+        "[NSID]nsid 0 (iproute2 netns name: tenant)",
+    ]
+    .iter()
+    .filter_map(|line| parser.parse(line))
+    .collect();
+    assert_eq!(
+        events,
+        vec![
+            NetworkEvent::LinkAdded {
+                ifindex: 2,
+                ifname: "br9".into()
+            },
+            NetworkEvent::LinkChanged {
+                ifindex: 2,
+                ifname: "br9".into(),
+                operstate: "UNKNOWN".into()
+            },
+            NetworkEvent::AddrAdded {
+                ifname: "lo".into(),
+                addr: "10.1.2.3/24".parse().unwrap()
+            },
+            NetworkEvent::AddrRemoved {
+                ifname: "wg0".into(),
+                addr: "10.0.0.1/32".parse().unwrap()
+            },
+            NetworkEvent::RouteChanged {
+                destination: "10.9.0.0/16".into(),
+                dev: Some("lo".into()),
+                table: Some("100".into()),
+                removed: false
+            },
+            NetworkEvent::RouteChanged {
+                destination: "10.1.2.3".into(),
+                dev: Some("lo".into()),
+                table: Some("local".into()),
+                removed: true
+            },
+            NetworkEvent::RouteChanged {
+                destination: "default".into(),
+                dev: Some("eth0".into()),
+                table: None,
+                removed: false
+            },
+            NetworkEvent::LinkRemoved {
+                ifindex: 4,
+                ifname: "veth0".into()
+            },
+        ]
+    );
+    assert_eq!(
+        netns_monitor_parse("add tenant"),
+        Some(NetworkEvent::NetnsAdded {
+            name: "tenant".into()
+        })
+    );
+    assert_eq!(
+        netns_monitor_parse("delete tenant"),
+        Some(NetworkEvent::NetnsRemoved {
+            name: "tenant".into()
+        })
+    );
+}
+
+#[test]
+fn test_monitor_links_diff() {
+    let links = |json: &str| serde_json::from_str::<Vec<LinkStats>>(json).unwrap();
+    let before = links(
+        r#"[{"ifindex": 1, "ifname": "lo", "operstate": "UNKNOWN"},
+            {"ifindex": 2, "ifname": "br0", "operstate": "DOWN"}]"#,
+    );
+    let after = links(
+        r#"[{"ifindex": 1, "ifname": "lo", "operstate": "UNKNOWN"},
+            {"ifindex": 3, "ifname": "br1", "operstate": "DOWN"}]"#,
+    );
+    assert_eq!(
+        links_diff(&before, &after),
+        vec![
+            NetworkEvent::LinkRemoved {
+                ifindex: 2,
+                ifname: "br0".into()
+            },
+            NetworkEvent::LinkAdded {
+                ifindex: 3,
+                ifname: "br1".into()
+            },
+        ]
+    );
+    assert_eq!(links_diff(&after, &after), vec![]);
+}
+
+#[test]
+fn test_monitor_netlink_subscribed() {
+    let table = "sk               Eth Pid        Groups   Rmem     Wmem     Dump  Locks    Drops    Inode\n\
+                 000000005814579f 0   23568      00000111 0        0        0     2        0        47899\n\
+                 00000000687a0015 0   4160437931 00000000 0        0        0     2        0        47900\n";
+    assert!(netlink_subscribed_in(table, &[47899, 47900]));
+    assert!(!netlink_subscribed_in(table, &[47900]));
+    assert!(!netlink_subscribed_in(table, &[]));
+}
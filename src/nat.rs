@@ -0,0 +1,465 @@
+use crate::{
+    ip6tables_restore_noflush, ip6tables_save_state, iptables_restore_noflush, iptables_save_state,
+    nft_apply, nft_list_ruleset, IntoNetnsRef, IpFamily, IptablesRule, IptablesState, NetnsRef,
+    NftBatch, NftChain, NftCommand, NftFamily, NftObject, NftRule, NftTable, Protocol,
+};
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// Name of the nftables table holding the NAT rules created by this crate.
+pub const NFT_NAT_TABLE: &str = "fractal-nat";
+
+/// Prefix of the iptables chains holding the NAT rules created by this crate. These are
+/// managed rule by rule, so the prefix must not match [`crate::IPTABLES_CHAIN_PREFIX`]
+/// or [`crate::iptables_apply_owned`] would remove them.
+pub const NAT_CHAIN_PREFIX: &str = "FRACTALNAT-";
+
+/// Firewall implementation used to install rules.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallBackend {
+    Iptables,
+    Nftables,
+}
+
+/// NAT rule managed by this crate.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum NatRule {
+    /// Rewrite the source of traffic from `source` leaving through `interface` to the
+    /// address of that interface.
+    Masquerade { interface: String, source: IpNet },
+    /// Forward traffic arriving on a port of a local address to another address.
+    /// Traffic routed through the namespace to other hosts is left alone.
+    PortForward {
+        protocol: Protocol,
+        port: u16,
+        destination: SocketAddr,
+    },
+    /// Map an external address to an internal one in both directions.
+    OneToOne { external: IpAddr, internal: IpAddr },
+}
+
+impl NatRule {
+    /// Address family of this rule.
+    pub fn family(&self) -> Result<IpFamily> {
+        match self {
+            NatRule::Masquerade { source, .. } => Ok(IpFamily::of(&source.addr())),
+            NatRule::PortForward { destination, .. } => Ok(IpFamily::of(&destination.ip())),
+            NatRule::OneToOne { external, internal } => {
+                let family = IpFamily::of(external);
+                if family != IpFamily::of(internal) {
+                    return Err(anyhow!(
+                        "Address family of {external} and {internal} differs"
+                    ));
+                }
+                Ok(family)
+            }
+        }
+    }
+
+    /// Rules of the `nat` table implementing this rule. They live in the
+    /// `FRACTALNAT-PREROUTING` and `FRACTALNAT-POSTROUTING` chains.
+    pub fn iptables_rules(&self) -> Vec<IptablesRule> {
+        let prerouting = format!("{NAT_CHAIN_PREFIX}PREROUTING");
+        let postrouting = format!("{NAT_CHAIN_PREFIX}POSTROUTING");
+        match self {
+            NatRule::Masquerade { interface, source } => vec![IptablesRule::new(
+                &postrouting,
+                &["-s", &source.trunc().to_string(), "-o", interface],
+                "MASQUERADE",
+            )],
+            NatRule::PortForward {
+                protocol,
+                port,
+                destination,
+            } => {
+                let protocol = protocol.to_string();
+                vec![IptablesRule::new(
+                    &prerouting,
+                    &[
+                        "-p",
+                        &protocol,
+                        "-m",
+                        &protocol,
+                        "--dport",
+                        &port.to_string(),
+                        "-m",
+                        "addrtype",
+                        "--dst-type",
+                        "LOCAL",
+                    ],
+                    "DNAT",
+                )
+                .with_target_args(&["--to-destination", &destination.to_string()])]
+            }
+            NatRule::OneToOne { external, internal } => vec![
+                IptablesRule::new(&prerouting, &["-d", &host(external)], "DNAT")
+                    .with_target_args(&["--to-destination", &internal.to_string()]),
+                IptablesRule::new(&postrouting, &["-s", &host(internal)], "SNAT")
+                    .with_target_args(&["--to-source", &external.to_string()]),
+            ],
+        }
+    }
+
+    /// Chains and statements of the rules implementing this rule in the nftables
+    /// NAT table.
+    pub fn nft_rules(&self) -> Vec<(&'static str, Vec<Value>)> {
+        let ip = match self.family() {
+            Ok(IpFamily::V6) => "ip6",
+            _ => "ip",
+        };
+        let matches = |left: Value, right: Value| json!({"match": {"op": "==", "left": left, "right": right}});
+        let address = |field: &str| json!({"payload": {"protocol": ip, "field": field}});
+        match self {
+            NatRule::Masquerade { interface, source } => vec![(
+                "postrouting",
+                vec![
+                    matches(
+                        address("saddr"),
+                        json!({"prefix": {"addr": source.network().to_string(), "len": source.prefix_len()}}),
+                    ),
+                    matches(json!({"meta": {"key": "oifname"}}), json!(interface)),
+                    json!({ "masquerade": null }),
+                ],
+            )],
+            NatRule::PortForward {
+                protocol,
+                port,
+                destination,
+            } => vec![(
+                "prerouting",
+                vec![
+                    matches(
+                        json!({"payload": {"protocol": protocol.to_string(), "field": "dport"}}),
+                        json!(port),
+                    ),
+                    matches(
+                        json!({"fib": {"result": "type", "flags": ["daddr"]}}),
+                        json!("local"),
+                    ),
+                    json!({"dnat": {"addr": destination.ip().to_string(), "port": destination.port()}}),
+                ],
+            )],
+            NatRule::OneToOne { external, internal } => vec![
+                (
+                    "prerouting",
+                    vec![
+                        matches(address("daddr"), json!(external.to_string())),
+                        json!({"dnat": {"addr": internal.to_string()}}),
+                    ],
+                ),
+                (
+                    "postrouting",
+                    vec![
+                        matches(address("saddr"), json!(internal.to_string())),
+                        json!({"snat": {"addr": external.to_string()}}),
+                    ],
+                ),
+            ],
+        }
+    }
+}
+
+/// Address as a host prefix, the way iptables-save prints it.
+fn host(addr: &IpAddr) -> String {
+    IpNet::from(*addr).to_string()
+}
+
+/// Rendered as the comment identifying the nftables rules of a NAT rule.
+impl fmt::Display for NatRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatRule::Masquerade { interface, source } => {
+                write!(f, "fractal masquerade {source} {interface}")
+            }
+            NatRule::PortForward {
+                protocol,
+                port,
+                destination,
+            } => write!(f, "fractal forward {protocol} {port} {destination}"),
+            NatRule::OneToOne { external, internal } => {
+                write!(f, "fractal 1:1 {external} {internal}")
+            }
+        }
+    }
+}
+
+/// Render the `iptables-restore --noflush` input adding or removing the rules of a NAT
+/// rule, given the current state. Chains and jumps are created when adding rules.
+/// Returns an empty string if there is nothing to change.
+fn nat_iptables_update(current: &IptablesState, rule: &NatRule, add: bool) -> String {
+    let mut lines = vec![];
+    let table = current.table("nat").cloned().unwrap_or_default();
+    let rules = rule.iptables_rules();
+    if add {
+        for builtin in ["PREROUTING", "POSTROUTING"] {
+            let chain = format!("{NAT_CHAIN_PREFIX}{builtin}");
+            if !rules.iter().any(|rule| rule.chain == chain) {
+                continue;
+            }
+            if table.chain(&chain).is_none() {
+                lines.push(format!(":{chain} - [0:0]"));
+            }
+            let jump = IptablesRule::new(builtin, &[], &chain);
+            if !table.contains_rule(&jump) {
+                lines.push(jump.command("-I", Some(1)));
+            }
+        }
+    }
+    for rule in &rules {
+        match (add, table.contains_rule(rule)) {
+            (true, false) => lines.push(rule.command("-A", None)),
+            (false, true) => lines.push(rule.command("-D", None)),
+            _ => {}
+        }
+    }
+    if lines.is_empty() {
+        return String::new();
+    }
+    format!("*nat\n{}\nCOMMIT\n", lines.join("\n"))
+}
+
+async fn nat_iptables(netns: &Option<NetnsRef>, rule: &NatRule, add: bool) -> Result<()> {
+    let update = match rule.family()? {
+        IpFamily::V4 => nat_iptables_update(&iptables_save_state(netns).await?, rule, add),
+        IpFamily::V6 => nat_iptables_update(&ip6tables_save_state(netns).await?, rule, add),
+    };
+    if update.is_empty() {
+        return Ok(());
+    }
+    match rule.family()? {
+        IpFamily::V4 => iptables_restore_noflush(netns, &update).await,
+        IpFamily::V6 => ip6tables_restore_noflush(netns, &update).await,
+    }
+}
+
+async fn nat_nftables(netns: &Option<NetnsRef>, rule: &NatRule, add: bool) -> Result<()> {
+    let family = match rule.family()? {
+        IpFamily::V4 => NftFamily::Ip,
+        IpFamily::V6 => NftFamily::Ip6,
+    };
+    let comment = rule.to_string();
+    let ruleset = nft_list_ruleset(netns).await?;
+    let existing: Vec<&NftRule> = ruleset
+        .rules
+        .iter()
+        .filter(|existing| existing.family == family && existing.table == NFT_NAT_TABLE)
+        .filter(|existing| existing.comment.as_deref() == Some(comment.as_str()))
+        .collect();
+    let mut batch = NftBatch::new();
+    if add {
+        if !existing.is_empty() {
+            return Ok(());
+        }
+        batch.push(NftCommand::Add(NftObject::Table(NftTable::new(
+            family,
+            NFT_NAT_TABLE,
+        ))));
+        for (chain, prio) in [("prerouting", -100), ("postrouting", 100)] {
+            batch.push(NftCommand::Add(NftObject::Chain(
+                NftChain::new(family, NFT_NAT_TABLE, chain).with_hook("nat", chain, prio, "accept"),
+            )));
+        }
+        for (chain, expr) in rule.nft_rules() {
+            let mut rule = NftRule::new(family, NFT_NAT_TABLE, chain, expr);
+            rule.comment = Some(comment.clone());
+            batch.push(NftCommand::Add(NftObject::Rule(rule)));
+        }
+    } else {
+        if existing.is_empty() {
+            return Ok(());
+        }
+        for rule in existing {
+            batch.push(NftCommand::Delete(NftObject::Rule(rule.clone())));
+        }
+    }
+    nft_apply(netns, &batch).await
+}
+
+/// Install a NAT rule unless it is already present.
+pub async fn nat_add(
+    netns: impl IntoNetnsRef,
+    backend: FirewallBackend,
+    rule: &NatRule,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("nat_add({netns:?}, {backend:?}, {rule})");
+    match backend {
+        FirewallBackend::Iptables => nat_iptables(netns, rule, true).await,
+        FirewallBackend::Nftables => nat_nftables(netns, rule, true).await,
+    }
+}
+
+/// Remove a NAT rule, if it is present.
+pub async fn nat_remove(
+    netns: impl IntoNetnsRef,
+    backend: FirewallBackend,
+    rule: &NatRule,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("nat_remove({netns:?}, {backend:?}, {rule})");
+    match backend {
+        FirewallBackend::Iptables => nat_iptables(netns, rule, false).await,
+        FirewallBackend::Nftables => nat_nftables(netns, rule, false).await,
+    }
+}
+
+/// Masquerade traffic from `source` leaving through `out_iface`. Returns the rule,
+/// which can be passed to [`nat_remove`].
+pub async fn nat_masquerade(
+    netns: impl IntoNetnsRef,
+    backend: FirewallBackend,
+    out_iface: &str,
+    source: IpNet,
+) -> Result<NatRule> {
+    let netns = &netns.into_netns_ref();
+    let rule = NatRule::Masquerade {
+        interface: out_iface.to_string(),
+        source,
+    };
+    nat_add(netns, backend, &rule).await?;
+    Ok(rule)
+}
+
+/// Forward traffic arriving on `external_port` to `destination`.
+pub async fn nat_port_forward(
+    netns: impl IntoNetnsRef,
+    backend: FirewallBackend,
+    protocol: Protocol,
+    external_port: u16,
+    destination: SocketAddr,
+) -> Result<NatRule> {
+    let netns = &netns.into_netns_ref();
+    let rule = NatRule::PortForward {
+        protocol,
+        port: external_port,
+        destination,
+    };
+    nat_add(netns, backend, &rule).await?;
+    Ok(rule)
+}
+
+/// Map `external` to `internal` in both directions.
+pub async fn nat_one_to_one(
+    netns: impl IntoNetnsRef,
+    backend: FirewallBackend,
+    external: IpAddr,
+    internal: IpAddr,
+) -> Result<NatRule> {
+    let netns = &netns.into_netns_ref();
+    let rule = NatRule::OneToOne { external, internal };
+    rule.family()?;
+    nat_add(netns, backend, &rule).await?;
+    Ok(rule)
+}
+
+#[test]
+fn test_nat_iptables_update() {
+    use std::str::FromStr;
+    let masquerade = NatRule::Masquerade {
+        interface: "eth0".to_string(),
+        source: "10.0.0.1/8".parse().unwrap(),
+    };
+    let forward = NatRule::PortForward {
+        protocol: Protocol::Tcp,
+        port: 80,
+        destination: "10.0.0.2:8080".parse().unwrap(),
+    };
+    let one_to_one = NatRule::OneToOne {
+        external: "fd00::1".parse().unwrap(),
+        internal: "fd01::1".parse().unwrap(),
+    };
+    let state = IptablesState::from_str(
+        "*nat\n\
+         :PREROUTING ACCEPT [0:0]\n\
+         :POSTROUTING ACCEPT [0:0]\n\
+         :FRACTALNAT-POSTROUTING - [0:0]\n\
+         -A POSTROUTING -j FRACTALNAT-POSTROUTING\n\
+         -A POSTROUTING -s 172.17.0.0/16 ! -o docker0 -j MASQUERADE\n\
+         -A FRACTALNAT-POSTROUTING -s 10.0.0.0/8 -o eth0 -j MASQUERADE\n\
+         COMMIT\n",
+    )
+    .unwrap();
+
+    // already present
+    assert_eq!(nat_iptables_update(&state, &masquerade, true), "");
+    assert_eq!(
+        nat_iptables_update(&state, &masquerade, false),
+        "*nat\n-D FRACTALNAT-POSTROUTING -s 10.0.0.0/8 -o eth0 -j MASQUERADE\nCOMMIT\n"
+    );
+
+    // chain and jump are created
+    assert_eq!(
+        nat_iptables_update(&state, &forward, true),
+        "*nat\n\
+         :FRACTALNAT-PREROUTING - [0:0]\n\
+         -I PREROUTING 1 -j FRACTALNAT-PREROUTING\n\
+         -A FRACTALNAT-PREROUTING -p tcp -m tcp --dport 80 -m addrtype --dst-type LOCAL -j DNAT --to-destination 10.0.0.2:8080\n\
+         COMMIT\n"
+    );
+    assert_eq!(nat_iptables_update(&state, &forward, false), "");
+
+    assert_eq!(
+        nat_iptables_update(&IptablesState::default(), &one_to_one, true),
+        "*nat\n\
+         :FRACTALNAT-PREROUTING - [0:0]\n\
+         -I PREROUTING 1 -j FRACTALNAT-PREROUTING\n\
+         :FRACTALNAT-POSTROUTING - [0:0]\n\
+         -I POSTROUTING 1 -j FRACTALNAT-POSTROUTING\n\
+         -A FRACTALNAT-PREROUTING -d fd00::1/128 -j DNAT --to-destination fd01::1\n\
+         -A FRACTALNAT-POSTROUTING -s fd01::1/128 -j SNAT --to-source fd00::1\n\
+         COMMIT\n"
+    );
+}
+
+#[test]
+fn test_nat_chains_not_owned() {
+    use crate::IPTABLES_CHAIN_PREFIX;
+    use std::str::FromStr;
+    assert!(!NAT_CHAIN_PREFIX.starts_with(IPTABLES_CHAIN_PREFIX));
+
+    let current = IptablesState::from_str(
+        "*nat\n\
+         :POSTROUTING ACCEPT [0:0]\n\
+         :FRACTALNAT-POSTROUTING - [0:0]\n\
+         -A POSTROUTING -j FRACTALNAT-POSTROUTING\n\
+         -A FRACTALNAT-POSTROUTING -s 10.0.0.0/8 -o eth0 -j MASQUERADE\n\
+         COMMIT\n",
+    )
+    .unwrap();
+    let mut desired = IptablesState::default();
+    desired.table_mut("nat");
+    assert_eq!(current.owned_update(IPTABLES_CHAIN_PREFIX, &desired), "");
+}
+
+#[test]
+fn test_nat_rule_family() {
+    let rule = NatRule::OneToOne {
+        external: "1.2.3.4".parse().unwrap(),
+        internal: "fd00::1".parse().unwrap(),
+    };
+    assert!(rule.family().is_err());
+    let rule = NatRule::PortForward {
+        protocol: Protocol::Udp,
+        port: 53,
+        destination: "[fd00::2]:53".parse().unwrap(),
+    };
+    assert_eq!(rule.family().unwrap(), IpFamily::V6);
+    assert_eq!(
+        rule.nft_rules(),
+        vec![(
+            "prerouting",
+            vec![
+                json!({"match": {"op": "==", "left": {"payload": {"protocol": "udp", "field": "dport"}}, "right": 53}}),
+                json!({"match": {"op": "==", "left": {"fib": {"result": "type", "flags": ["daddr"]}}, "right": "local"}}),
+                json!({"dnat": {"addr": "fd00::2", "port": 53}}),
+            ]
+        )]
+    );
+}
@@ -0,0 +1,535 @@
+use crate::*;
+use anyhow::Result;
+use ipnet::IpNet;
+use log::*;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// Reference to a network namespace. Besides namespaces named with `ip netns`, this
+/// can refer to the unnamed namespaces of containers through one of their processes,
+/// a namespace file such as `/proc/<pid>/ns/net` or an open file descriptor of one.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NetnsRef {
+    /// Namespace named with `ip netns`, mounted under [`NETNS_RUN_PATH`].
+    Named(String),
+    /// Namespace of a process.
+    Pid(u32),
+    /// Namespace file, should be an absolute path.
+    Path(PathBuf),
+    /// Open file descriptor of a namespace file in this process. The descriptor is
+    /// neither owned nor closed, it has to stay open as long as the reference is used,
+    /// otherwise it may refer to whatever file reuses the number.
+    Fd(RawFd),
+}
+
+impl NetnsRef {
+    /// Path of the namespace file.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            NetnsRef::Named(name) => Path::new(NETNS_RUN_PATH).join(name),
+            NetnsRef::Pid(pid) => PathBuf::from(format!("/proc/{pid}/ns/net")),
+            NetnsRef::Path(path) => path.clone(),
+            NetnsRef::Fd(fd) => PathBuf::from(format!("/proc/{}/fd/{fd}", std::process::id())),
+        }
+    }
+
+    /// Argument for the `netns` option of `ip link`, which takes a name, a PID or a
+    /// path containing a slash.
+    pub(crate) fn link_arg(&self) -> OsString {
+        match self {
+            NetnsRef::Named(name) => name.into(),
+            NetnsRef::Pid(pid) => pid.to_string().into(),
+            other => other.path().into(),
+        }
+    }
+}
+
+impl std::fmt::Display for NetnsRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetnsRef::Named(name) => write!(f, "{name}"),
+            NetnsRef::Pid(pid) => write!(f, "pid {pid}"),
+            NetnsRef::Path(path) => write!(f, "{}", path.display()),
+            NetnsRef::Fd(fd) => write!(f, "fd {fd}"),
+        }
+    }
+}
+
+/// Network namespace argument of the wrappers, `None` refers to the current namespace.
+/// Namespace names can be passed as `&str` or `Option<&str>`, other references as
+/// [`NetnsRef`].
+pub trait IntoNetnsRef {
+    fn into_netns_ref(self) -> Option<NetnsRef>;
+}
+
+impl IntoNetnsRef for Option<&str> {
+    fn into_netns_ref(self) -> Option<NetnsRef> {
+        self.map(|name| NetnsRef::Named(name.to_string()))
+    }
+}
+
+impl IntoNetnsRef for &str {
+    fn into_netns_ref(self) -> Option<NetnsRef> {
+        Some(NetnsRef::Named(self.to_string()))
+    }
+}
+
+impl IntoNetnsRef for NetnsRef {
+    fn into_netns_ref(self) -> Option<NetnsRef> {
+        Some(self)
+    }
+}
+
+impl IntoNetnsRef for &NetnsRef {
+    fn into_netns_ref(self) -> Option<NetnsRef> {
+        Some(self.clone())
+    }
+}
+
+impl IntoNetnsRef for &Option<NetnsRef> {
+    fn into_netns_ref(self) -> Option<NetnsRef> {
+        self.clone()
+    }
+}
+
+/// Delete a network namespace without an async runtime, for use in [`Drop`].
+fn netns_del_blocking(name: &str) {
+    info!("netns del {name}");
+    let status = std::process::Command::new(IP_PATH)
+        .arg("netns")
+        .arg("del")
+        .arg(name)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(_) => warn!("Error deleting netns {name}"),
+        Err(error) => warn!("Error deleting netns {name}: {error}"),
+    }
+}
+
+/// Delete an interface without an async runtime, for use in [`Drop`].
+fn interface_del_blocking(netns: &Option<NetnsRef>, interface: &str) {
+    info!("interface_del({:?}, {})", netns, interface);
+    let status = ip_command_blocking(netns)
+        .arg("link")
+        .arg("del")
+        .arg("dev")
+        .arg(interface)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(_) => warn!("Error removing interface {interface} in {netns:?}"),
+        Err(error) => warn!("Error removing interface {interface} in {netns:?}: {error}"),
+    }
+}
+
+/// Handle to a named network namespace.
+///
+/// Namespaces created with [`NetworkNamespace::create`] are deleted when the handle is
+/// dropped, so they do not leak when a task panics or returns early. Dropping deletes
+/// the namespace synchronously and only logs errors, use [`NetworkNamespace::close`] to
+/// tear it down from async code and see whether that worked.
+///
+/// Since dropping waits for `ip netns del`, dropping the handle inside an async task
+/// blocks the runtime worker thread until it exits. That is fine as a fallback for
+/// tasks that fail, but handles should normally be closed.
+#[derive(Debug)]
+pub struct NetworkNamespace {
+    name: String,
+    delete_on_drop: bool,
+}
+
+impl NetworkNamespace {
+    /// Create a new network namespace, which is deleted when the handle is dropped.
+    pub async fn create(name: &str) -> Result<Self> {
+        netns_add(name).await?;
+        Ok(NetworkNamespace {
+            name: name.to_string(),
+            delete_on_drop: true,
+        })
+    }
+
+    /// Handle to an existing network namespace, which is kept when the handle is dropped.
+    pub fn open(name: &str) -> Self {
+        NetworkNamespace {
+            name: name.to_string(),
+            delete_on_drop: false,
+        }
+    }
+
+    /// Choose whether the namespace is deleted when the handle is dropped.
+    pub fn with_delete_on_drop(mut self, delete_on_drop: bool) -> Self {
+        self.delete_on_drop = delete_on_drop;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn netns(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    /// Whether the namespace is deleted when the handle is dropped.
+    pub fn delete_on_drop(&self) -> bool {
+        self.delete_on_drop
+    }
+
+    /// Close the handle, deleting the namespace if it would have been deleted on drop.
+    pub async fn close(mut self) -> Result<()> {
+        if std::mem::take(&mut self.delete_on_drop) {
+            netns_del(&self.name).await?;
+        }
+        Ok(())
+    }
+
+    /// Build a command which runs `program` inside this namespace.
+    pub fn command(&self, program: &str) -> Command {
+        netns_command(&self.netns().into_netns_ref(), program)
+    }
+
+    pub async fn exists(&self) -> Result<bool> {
+        netns_exists(&self.name).await
+    }
+
+    pub async fn write_file(&self, filename: &Path, data: &str) -> Result<()> {
+        netns_write_file(&self.name, filename, data).await
+    }
+
+    pub async fn write_file_secret(&self, filename: &Path, data: &str) -> Result<()> {
+        netns_write_file_secret(&self.name, filename, data).await
+    }
+
+    /// Get a handle to an existing interface in this namespace, which is kept when the
+    /// handle is dropped.
+    pub fn interface(&self, name: &str) -> Interface {
+        Interface::open(self.netns(), name)
+    }
+
+    pub async fn addr_add(&self, interface: &str, addr: IpNet) -> Result<()> {
+        addr_add(self.netns(), interface, addr).await
+    }
+
+    pub async fn addr_list(&self, interface: &str) -> Result<Vec<IpNet>> {
+        addr_list(self.netns(), interface).await
+    }
+
+    pub async fn interface_show(&self, interface: &str) -> Result<InterfaceShow> {
+        interface_show(self.netns(), interface).await
+    }
+
+    pub async fn interface_up(&self, interface: &str) -> Result<()> {
+        interface_up(self.netns(), interface).await
+    }
+
+    pub async fn interface_del(&self, interface: &str) -> Result<()> {
+        interface_del(self.netns(), interface).await
+    }
+
+    pub async fn interface_mtu(&self, interface: &str, mtu: usize) -> Result<()> {
+        interface_mtu(self.netns(), interface, mtu).await
+    }
+
+    pub async fn link_stats(&self) -> Result<Vec<LinkStats>> {
+        link_stats(self.netns()).await
+    }
+
+    /// List the links of this namespace whose peers are in other namespaces.
+    pub async fn link_peers(&self) -> Result<Vec<LinkPeer>> {
+        link_peers(self.netns()).await
+    }
+
+    pub async fn link_get_master(&self, interface: &str) -> Result<Option<String>> {
+        link_get_master(self.netns(), interface).await
+    }
+
+    pub async fn link_set_master(&self, interface: &str, master: &str) -> Result<()> {
+        link_set_master(self.netns(), interface, master).await
+    }
+
+    pub async fn route_replace(
+        &self,
+        destination: IpNet,
+        interface: &str,
+        table: Option<&str>,
+    ) -> Result<()> {
+        route_replace(self.netns(), destination, interface, table).await
+    }
+
+    pub async fn route_del(
+        &self,
+        destination: IpNet,
+        interface: &str,
+        table: Option<&str>,
+    ) -> Result<()> {
+        route_del(self.netns(), destination, interface, table).await
+    }
+
+    /// Create a bridge in this namespace, which is deleted when the handle is dropped.
+    pub async fn bridge_add(&self, name: &str) -> Result<Interface> {
+        bridge_add(self.netns(), name).await?;
+        Ok(Interface::owned(self.netns(), name))
+    }
+
+    pub async fn bridge_exists(&self, name: &str) -> Result<bool> {
+        bridge_exists(self.netns(), name).await
+    }
+
+    /// Create a veth pair with `outer` in the current namespace and `inner` in this one.
+    /// Deleting either end deletes the pair, so only the outer handle deletes it on drop.
+    pub async fn veth_add(&self, outer: &str, inner: &str) -> Result<(Interface, Interface)> {
+        veth_add(self.netns(), outer, inner).await?;
+        Ok((
+            Interface::owned(None, outer),
+            Interface::open(self.netns(), inner),
+        ))
+    }
+
+    pub async fn veth_exists(&self, name: &str) -> Result<bool> {
+        veth_exists(self.netns(), name).await
+    }
+
+    /// Create a wireguard interface in this namespace, see [`wireguard_create`]. It is
+    /// deleted when the handle is dropped.
+    pub async fn wireguard_create(&self, name: &str) -> Result<Interface> {
+        wireguard_create(self.netns(), name).await?;
+        Ok(Interface::owned(self.netns(), name))
+    }
+
+    pub async fn wireguard_exists(&self, name: &str) -> Result<bool> {
+        wireguard_exists(self.netns(), name).await
+    }
+
+    pub async fn wireguard_syncconf(&self, name: &str) -> Result<()> {
+        wireguard_syncconf(&self.name, name).await
+    }
+
+    pub async fn wireguard_stats(&self, name: &str) -> Result<NetworkStats> {
+        wireguard_stats(self.netns(), name).await
+    }
+
+    pub async fn wireguard_stats_all(&self) -> Result<BTreeMap<String, NetworkStats>> {
+        wireguard_stats_all(self.netns()).await
+    }
+
+    pub async fn wireguard_up(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        wireguard_up(&self.name, name, config).await
+    }
+
+    pub async fn wireguard_down(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        wireguard_down(&self.name, name, config).await
+    }
+
+    pub async fn iptables_save(&self) -> Result<String> {
+        iptables_save(self.netns()).await
+    }
+
+    pub async fn iptables_restore(&self, state: &str) -> Result<()> {
+        iptables_restore(self.netns(), state).await
+    }
+
+    pub async fn iptables_restore_noflush(&self, state: &str) -> Result<()> {
+        iptables_restore_noflush(self.netns(), state).await
+    }
+
+    pub async fn ip6tables_save(&self) -> Result<String> {
+        ip6tables_save(self.netns()).await
+    }
+
+    pub async fn ip6tables_restore(&self, state: &str) -> Result<()> {
+        ip6tables_restore(self.netns(), state).await
+    }
+
+    pub async fn ip6tables_restore_noflush(&self, state: &str) -> Result<()> {
+        ip6tables_restore_noflush(self.netns(), state).await
+    }
+
+    pub async fn iptables_save_state(&self) -> Result<IptablesState> {
+        iptables_save_state(self.netns()).await
+    }
+
+    pub async fn iptables_restore_state(&self, state: &IptablesState) -> Result<()> {
+        iptables_restore_state(self.netns(), state).await
+    }
+
+    pub async fn nft_list_ruleset(&self) -> Result<NftRuleset> {
+        nft_list_ruleset(self.netns()).await
+    }
+
+    pub async fn nft_apply(&self, batch: &NftBatch) -> Result<()> {
+        nft_apply(self.netns(), batch).await
+    }
+
+    pub async fn nat_add(&self, backend: FirewallBackend, rule: &NatRule) -> Result<()> {
+        nat_add(self.netns(), backend, rule).await
+    }
+
+    pub async fn nat_remove(&self, backend: FirewallBackend, rule: &NatRule) -> Result<()> {
+        nat_remove(self.netns(), backend, rule).await
+    }
+
+    pub async fn conntrack_flush(&self) -> Result<()> {
+        conntrack_flush(self.netns()).await
+    }
+}
+
+impl Drop for NetworkNamespace {
+    fn drop(&mut self) {
+        if self.delete_on_drop {
+            netns_del_blocking(&self.name);
+        }
+    }
+}
+
+/// Handle to a network interface.
+///
+/// Interfaces created through a [`NetworkNamespace`] are deleted when the handle is
+/// dropped, like the namespace itself. Interfaces inside a namespace disappear together
+/// with it, so handles should be dropped or closed before their namespace. Like for
+/// namespaces, dropping blocks the current thread until the interface is deleted, use
+/// [`Interface::close`] from async code.
+#[derive(Debug)]
+pub struct Interface {
+    netns: Option<NetnsRef>,
+    name: String,
+    delete_on_drop: bool,
+}
+
+impl Interface {
+    fn owned(netns: impl IntoNetnsRef, name: &str) -> Self {
+        Interface::open(netns, name).with_delete_on_drop(true)
+    }
+
+    /// Handle to an existing interface, which is kept when the handle is dropped.
+    pub fn open(netns: impl IntoNetnsRef, name: &str) -> Self {
+        Interface {
+            netns: netns.into_netns_ref(),
+            name: name.to_string(),
+            delete_on_drop: false,
+        }
+    }
+
+    /// Create a bridge, which is deleted when the handle is dropped.
+    pub async fn bridge(netns: impl IntoNetnsRef, name: &str) -> Result<Self> {
+        let netns = &netns.into_netns_ref();
+        bridge_add(netns, name).await?;
+        Ok(Interface::owned(netns, name))
+    }
+
+    /// Create a wireguard interface, see [`wireguard_create`]. It is deleted when the
+    /// handle is dropped.
+    pub async fn wireguard(netns: impl IntoNetnsRef, name: &str) -> Result<Self> {
+        let netns = &netns.into_netns_ref();
+        wireguard_create(netns, name).await?;
+        Ok(Interface::owned(netns, name))
+    }
+
+    /// Choose whether the interface is deleted when the handle is dropped.
+    pub fn with_delete_on_drop(mut self, delete_on_drop: bool) -> Self {
+        self.delete_on_drop = delete_on_drop;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn netns(&self) -> Option<&NetnsRef> {
+        self.netns.as_ref()
+    }
+
+    /// Whether the interface is deleted when the handle is dropped.
+    pub fn delete_on_drop(&self) -> bool {
+        self.delete_on_drop
+    }
+
+    /// Close the handle, deleting the interface if it would have been deleted on drop.
+    pub async fn close(mut self) -> Result<()> {
+        if std::mem::take(&mut self.delete_on_drop) {
+            interface_del(&self.netns, &self.name).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn show(&self) -> Result<InterfaceShow> {
+        interface_show(&self.netns, &self.name).await
+    }
+
+    pub async fn up(&self) -> Result<()> {
+        interface_up(&self.netns, &self.name).await
+    }
+
+    pub async fn mtu(&self, mtu: usize) -> Result<()> {
+        interface_mtu(&self.netns, &self.name, mtu).await
+    }
+
+    pub async fn addr_add(&self, addr: IpNet) -> Result<()> {
+        addr_add(&self.netns, &self.name, addr).await
+    }
+
+    pub async fn addr_list(&self) -> Result<Vec<IpNet>> {
+        addr_list(&self.netns, &self.name).await
+    }
+
+    pub async fn master(&self) -> Result<Option<String>> {
+        link_get_master(&self.netns, &self.name).await
+    }
+
+    pub async fn set_master(&self, master: &str) -> Result<()> {
+        link_set_master(&self.netns, &self.name, master).await
+    }
+
+    pub async fn route_replace(&self, destination: IpNet, table: Option<&str>) -> Result<()> {
+        route_replace(&self.netns, destination, &self.name, table).await
+    }
+
+    pub async fn route_del(&self, destination: IpNet, table: Option<&str>) -> Result<()> {
+        route_del(&self.netns, destination, &self.name, table).await
+    }
+}
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        if self.delete_on_drop {
+            interface_del_blocking(&self.netns, &self.name);
+        }
+    }
+}
+
+#[test]
+fn test_netns_ref() {
+    let named = NetnsRef::Named("tenant".to_string());
+    assert_eq!(named.path(), Path::new("/var/run/netns/tenant"));
+    assert_eq!(named.link_arg(), "tenant");
+    assert_eq!(NetnsRef::Pid(42).path(), Path::new("/proc/42/ns/net"));
+    assert_eq!(NetnsRef::Pid(42).link_arg(), "42");
+    assert_eq!(
+        NetnsRef::Fd(3).link_arg(),
+        format!("/proc/{}/fd/3", std::process::id()).as_str()
+    );
+    assert_eq!(Some("tenant").into_netns_ref(), Some(named.clone()));
+    assert_eq!(None::<&str>.into_netns_ref(), None);
+    assert_eq!((&named).into_netns_ref(), Some(named));
+}
+
+#[test]
+fn test_netns_command() {
+    let command = ip_command_blocking(&Some(NetnsRef::Pid(42)));
+    assert_eq!(command.get_program(), NSENTER_PATH);
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        ["--net=/proc/42/ns/net", IP_PATH]
+    );
+    let command = ip_command_blocking(&Some(NetnsRef::Named("tenant".to_string())));
+    assert_eq!(command.get_program(), IP_PATH);
+    assert_eq!(command.get_args().collect::<Vec<_>>(), ["-n", "tenant"]);
+}
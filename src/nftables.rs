@@ -0,0 +1,592 @@
+use crate::{netns_command, IntoNetnsRef, NFT_PATH};
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use log::*;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+
+/// Address family of an nftables table.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum NftFamily {
+    Ip,
+    Ip6,
+    Inet,
+    Arp,
+    Bridge,
+    Netdev,
+}
+
+/// nftables table.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NftTable {
+    pub family: NftFamily,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<u64>,
+}
+
+impl NftTable {
+    pub fn new(family: NftFamily, name: &str) -> Self {
+        NftTable {
+            family,
+            name: name.to_string(),
+            handle: None,
+        }
+    }
+}
+
+/// nftables chain. Base chains are attached to a hook and have a type, priority and
+/// policy, regular chains only have a name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NftChain {
+    pub family: NftFamily,
+    pub table: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<u64>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub chain_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prio: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    /// Devices of netdev chains.
+    #[serde(
+        default,
+        deserialize_with = "string_or_seq",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub dev: Vec<String>,
+}
+
+impl NftChain {
+    /// Create a regular chain.
+    pub fn new(family: NftFamily, table: &str, name: &str) -> Self {
+        NftChain {
+            family,
+            table: table.to_string(),
+            name: name.to_string(),
+            handle: None,
+            chain_type: None,
+            hook: None,
+            prio: None,
+            policy: None,
+            dev: vec![],
+        }
+    }
+
+    /// Turn this chain into a base chain attached to a hook.
+    pub fn with_hook(mut self, chain_type: &str, hook: &str, prio: i64, policy: &str) -> Self {
+        self.chain_type = Some(chain_type.to_string());
+        self.hook = Some(hook.to_string());
+        self.prio = Some(prio);
+        self.policy = Some(policy.to_string());
+        self
+    }
+}
+
+/// Deserialize a list which nft prints as a plain string when it has a single entry.
+fn string_or_seq<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrSeq {
+        String(String),
+        Seq(Vec<String>),
+    }
+    Ok(match StringOrSeq::deserialize(deserializer)? {
+        StringOrSeq::String(value) => vec![value],
+        StringOrSeq::Seq(values) => values,
+    })
+}
+
+/// nftables rule. Statements are kept in the JSON expression syntax of `nft -j`,
+/// which is too large to model completely.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NftRule {
+    pub family: NftFamily,
+    pub table: String,
+    pub chain: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub expr: Vec<Value>,
+}
+
+impl NftRule {
+    pub fn new(family: NftFamily, table: &str, chain: &str, expr: Vec<Value>) -> Self {
+        NftRule {
+            family,
+            table: table.to_string(),
+            chain: chain.to_string(),
+            handle: None,
+            comment: None,
+            expr,
+        }
+    }
+}
+
+/// Type of set keys or map values, either a single type such as `ipv4_addr` or a
+/// concatenation such as `ipv4_addr . inet_service`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum NftSetType {
+    Single(String),
+    Concat(Vec<String>),
+}
+
+impl From<&str> for NftSetType {
+    fn from(value: &str) -> Self {
+        NftSetType::Single(value.to_string())
+    }
+}
+
+/// nftables set, or map if `map` is set to the type of the values.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NftSet {
+    pub family: NftFamily,
+    pub table: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<u64>,
+    #[serde(rename = "type")]
+    pub set_type: NftSetType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub map: Option<NftSetType>,
+    #[serde(
+        default,
+        deserialize_with = "string_or_seq",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub flags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub elem: Vec<Value>,
+}
+
+impl NftSet {
+    pub fn new(family: NftFamily, table: &str, name: &str, set_type: NftSetType) -> Self {
+        NftSet {
+            family,
+            table: table.to_string(),
+            name: name.to_string(),
+            handle: None,
+            set_type,
+            map: None,
+            flags: vec![],
+            elem: vec![],
+        }
+    }
+
+    /// Create a map from keys of `set_type` to values of `map`.
+    pub fn new_map(
+        family: NftFamily,
+        table: &str,
+        name: &str,
+        set_type: NftSetType,
+        map: NftSetType,
+    ) -> Self {
+        NftSet {
+            map: Some(map),
+            ..NftSet::new(family, table, name, set_type)
+        }
+    }
+
+    /// Add flags such as `interval`, which is needed for sets holding prefixes.
+    pub fn with_flags(mut self, flags: &[&str]) -> Self {
+        self.flags = flags.iter().map(|flag| flag.to_string()).collect();
+        self
+    }
+}
+
+/// Elements of a set or map, used to add or delete elements.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NftElements {
+    pub family: NftFamily,
+    pub table: String,
+    pub name: String,
+    pub elem: Vec<Value>,
+}
+
+/// Set element matching an address or a network. Networks other than single
+/// addresses need a set with the `interval` flag.
+pub fn nft_element_net(net: &IpNet) -> Value {
+    if net.prefix_len() == net.max_prefix_len() {
+        json!(net.addr().to_string())
+    } else {
+        json!({"prefix": {"addr": net.network().to_string(), "len": net.prefix_len()}})
+    }
+}
+
+/// Map element from a key to a value.
+pub fn nft_element_map(key: Value, value: Value) -> Value {
+    json!([key, value])
+}
+
+/// Object of the nftables JSON syntax, as listed by `nft -j list ruleset` and used
+/// in commands.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NftObject {
+    Table(NftTable),
+    Chain(NftChain),
+    Rule(NftRule),
+    Set(NftSet),
+    Map(NftSet),
+    Element(NftElements),
+}
+
+/// Command of an nftables batch.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NftCommand {
+    Add(NftObject),
+    Create(NftObject),
+    Insert(NftObject),
+    Replace(NftObject),
+    Delete(NftObject),
+    Flush(NftObject),
+}
+
+/// Batch of nftables commands, which nft applies atomically: either all commands
+/// succeed or none of them is applied.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct NftBatch {
+    pub commands: Vec<NftCommand>,
+}
+
+impl NftBatch {
+    pub fn new() -> Self {
+        NftBatch::default()
+    }
+
+    /// Add a command to this batch.
+    pub fn push(&mut self, command: NftCommand) -> &mut Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Add a set or map, depending on whether it has a value type.
+    pub fn add_set(&mut self, set: NftSet) -> &mut Self {
+        let object = match set.map {
+            Some(_) => NftObject::Map(set),
+            None => NftObject::Set(set),
+        };
+        self.push(NftCommand::Add(object))
+    }
+
+    /// Remove all elements of a set or map.
+    pub fn flush_set(&mut self, set: &NftSet) -> &mut Self {
+        let mut set = set.clone();
+        set.elem.clear();
+        let object = match set.map {
+            Some(_) => NftObject::Map(set),
+            None => NftObject::Set(set),
+        };
+        self.push(NftCommand::Flush(object))
+    }
+
+    /// Add elements to a set or map.
+    pub fn add_elements(&mut self, set: &NftSet, elem: Vec<Value>) -> &mut Self {
+        self.push(NftCommand::Add(NftObject::Element(NftElements {
+            family: set.family,
+            table: set.table.clone(),
+            name: set.name.clone(),
+            elem,
+        })))
+    }
+
+    /// Delete elements from a set or map.
+    pub fn delete_elements(&mut self, set: &NftSet, elem: Vec<Value>) -> &mut Self {
+        self.push(NftCommand::Delete(NftObject::Element(NftElements {
+            family: set.family,
+            table: set.table.clone(),
+            name: set.name.clone(),
+            elem,
+        })))
+    }
+
+    /// Replace the elements of a set or map, creating it if it does not exist yet.
+    pub fn replace_elements(&mut self, set: &NftSet, elem: Vec<Value>) -> &mut Self {
+        let mut declaration = set.clone();
+        declaration.elem.clear();
+        self.add_set(declaration).flush_set(set);
+        if !elem.is_empty() {
+            self.add_elements(set, elem);
+        }
+        self
+    }
+
+    /// Render this batch in the JSON syntax accepted by `nft -j -f`.
+    pub fn to_json(&self) -> Value {
+        json!({ "nftables": self.commands })
+    }
+}
+
+/// Parsed nftables ruleset, as listed by `nft -j list ruleset`. Objects this crate
+/// does not model, such as counters or flowtables, are skipped.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct NftRuleset {
+    pub tables: Vec<NftTable>,
+    pub chains: Vec<NftChain>,
+    pub rules: Vec<NftRule>,
+    pub sets: Vec<NftSet>,
+    pub maps: Vec<NftSet>,
+}
+
+impl NftRuleset {
+    /// Look up a table.
+    pub fn table(&self, family: NftFamily, name: &str) -> Option<&NftTable> {
+        self.tables
+            .iter()
+            .find(|table| table.family == family && table.name == name)
+    }
+
+    /// Look up a chain.
+    pub fn chain(&self, family: NftFamily, table: &str, name: &str) -> Option<&NftChain> {
+        self.chains
+            .iter()
+            .find(|chain| chain.family == family && chain.table == table && chain.name == name)
+    }
+
+    /// Iterate over the rules of a chain, in order.
+    pub fn chain_rules<'a>(
+        &'a self,
+        family: NftFamily,
+        table: &'a str,
+        chain: &'a str,
+    ) -> impl Iterator<Item = &'a NftRule> {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.family == family && rule.table == table && rule.chain == chain)
+    }
+
+    /// Look up a set or map.
+    pub fn set(&self, family: NftFamily, table: &str, name: &str) -> Option<&NftSet> {
+        self.sets
+            .iter()
+            .chain(self.maps.iter())
+            .find(|set| set.family == family && set.table == table && set.name == name)
+    }
+}
+
+impl FromStr for NftRuleset {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        #[derive(Deserialize)]
+        struct Output {
+            nftables: Vec<serde_json::Map<String, Value>>,
+        }
+        let output: Output = serde_json::from_str(input)?;
+        let mut ruleset = NftRuleset::default();
+        for object in output.nftables {
+            let (kind, value) = match object.into_iter().next() {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let context = || format!("Parsing nftables {kind}");
+            match kind.as_str() {
+                "table" => ruleset
+                    .tables
+                    .push(serde_json::from_value(value).with_context(context)?),
+                "chain" => ruleset
+                    .chains
+                    .push(serde_json::from_value(value).with_context(context)?),
+                "rule" => ruleset
+                    .rules
+                    .push(serde_json::from_value(value).with_context(context)?),
+                "set" => ruleset
+                    .sets
+                    .push(serde_json::from_value(value).with_context(context)?),
+                "map" => ruleset
+                    .maps
+                    .push(serde_json::from_value(value).with_context(context)?),
+                _ => {}
+            }
+        }
+        Ok(ruleset)
+    }
+}
+
+/// List the nftables ruleset.
+pub async fn nft_list_ruleset(netns: impl IntoNetnsRef) -> Result<NftRuleset> {
+    let netns = &netns.into_netns_ref();
+    let output = netns_command(netns, NFT_PATH)
+        .arg("-j")
+        .arg("list")
+        .arg("ruleset")
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error listing nftables ruleset in {netns:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let output = String::from_utf8(output.stdout)?;
+    NftRuleset::from_str(&output).context("Parsing nft list ruleset output")
+}
+
+/// Apply a batch of nftables commands atomically.
+pub async fn nft_apply(netns: impl IntoNetnsRef, batch: &NftBatch) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("nft_apply({:?}, {})", netns, batch.commands.len());
+    let input = serde_json::to_string(&batch.to_json())?;
+    let mut handle = netns_command(netns, NFT_PATH)
+        .arg("-j")
+        .arg("-f")
+        .arg("-")
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = handle.stdin.take().unwrap();
+    stdin.write_all(input.as_bytes()).await?;
+    drop(stdin);
+    let output = handle.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error applying nftables batch in {netns:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Replace the elements of a set atomically, creating the set if needed.
+pub async fn nft_set_replace(
+    netns: impl IntoNetnsRef,
+    set: &NftSet,
+    elem: Vec<Value>,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    let mut batch = NftBatch::new();
+    batch.replace_elements(set, elem);
+    nft_apply(netns, &batch).await
+}
+
+/// Add elements to a set or map.
+pub async fn nft_set_add(netns: impl IntoNetnsRef, set: &NftSet, elem: Vec<Value>) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    let mut batch = NftBatch::new();
+    batch.add_elements(set, elem);
+    nft_apply(netns, &batch).await
+}
+
+/// Delete elements from a set or map.
+pub async fn nft_set_delete(
+    netns: impl IntoNetnsRef,
+    set: &NftSet,
+    elem: Vec<Value>,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    let mut batch = NftBatch::new();
+    batch.delete_elements(set, elem);
+    nft_apply(netns, &batch).await
+}
+
+#[cfg(test)]
+const TEST_RULESET: &str = r#"{"nftables": [{"metainfo": {"version": "1.0.2", "release_name": "Lester Gooch", "json_schema_version": 1}}, {"table": {"family": "inet", "name": "fractal", "handle": 3}}, {"chain": {"family": "inet", "table": "fractal", "name": "forward", "handle": 1, "type": "filter", "hook": "forward", "prio": 0, "policy": "drop"}}, {"chain": {"family": "inet", "table": "fractal", "name": "tenants", "handle": 2}}, {"set": {"family": "inet", "name": "allowed", "table": "fractal", "type": "ipv4_addr", "handle": 4, "flags": ["interval"], "elem": ["10.0.0.1", {"prefix": {"addr": "10.1.0.0", "len": 16}}]}}, {"map": {"family": "inet", "name": "ports", "table": "fractal", "type": ["ipv4_addr", "inet_service"], "handle": 5, "map": "verdict", "elem": [[{"concat": ["10.0.0.1", 80]}, {"accept": null}]]}}, {"counter": {"family": "inet", "name": "dropped", "table": "fractal", "handle": 6, "packets": 0, "bytes": 0}}, {"rule": {"family": "inet", "table": "fractal", "chain": "forward", "handle": 7, "comment": "tenants", "expr": [{"match": {"op": "==", "left": {"meta": {"key": "iifname"}}, "right": "wg0"}}, {"jump": {"target": "tenants"}}]}}]}"#;
+
+#[test]
+fn test_nft_ruleset_parse() {
+    let ruleset = NftRuleset::from_str(TEST_RULESET).unwrap();
+    assert_eq!(ruleset.tables.len(), 1);
+    assert_eq!(
+        ruleset.table(NftFamily::Inet, "fractal").unwrap().handle,
+        Some(3)
+    );
+
+    let forward = ruleset
+        .chain(NftFamily::Inet, "fractal", "forward")
+        .unwrap();
+    assert_eq!(forward.hook.as_deref(), Some("forward"));
+    assert_eq!(forward.policy.as_deref(), Some("drop"));
+    assert_eq!(
+        ruleset
+            .chain(NftFamily::Inet, "fractal", "tenants")
+            .unwrap()
+            .hook,
+        None
+    );
+
+    let rules: Vec<_> = ruleset
+        .chain_rules(NftFamily::Inet, "fractal", "forward")
+        .collect();
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0].comment.as_deref(), Some("tenants"));
+    assert_eq!(rules[0].expr[1], json!({"jump": {"target": "tenants"}}));
+
+    let allowed = ruleset.set(NftFamily::Inet, "fractal", "allowed").unwrap();
+    assert_eq!(allowed.set_type, "ipv4_addr".into());
+    assert_eq!(allowed.flags, vec!["interval"]);
+    assert_eq!(
+        allowed.elem,
+        vec![
+            nft_element_net(&"10.0.0.1/32".parse().unwrap()),
+            nft_element_net(&"10.1.0.0/16".parse().unwrap())
+        ]
+    );
+
+    let ports = ruleset.set(NftFamily::Inet, "fractal", "ports").unwrap();
+    assert_eq!(
+        ports.set_type,
+        NftSetType::Concat(vec!["ipv4_addr".into(), "inet_service".into()])
+    );
+    assert_eq!(ports.map, Some("verdict".into()));
+
+    assert!(NftRuleset::from_str(r#"{"nftables": [{"table": {"name": "x"}}]}"#).is_err());
+}
+
+#[test]
+fn test_nft_string_or_seq() {
+    let chain: NftChain = serde_json::from_str(
+        r#"{"family": "netdev", "table": "filter", "name": "ingress", "type": "filter", "hook": "ingress", "prio": 0, "policy": "accept", "dev": "eth0"}"#,
+    )
+    .unwrap();
+    assert_eq!(chain.dev, vec!["eth0"]);
+    let chain: NftChain = serde_json::from_str(
+        r#"{"family": "netdev", "table": "filter", "name": "ingress", "type": "filter", "hook": "ingress", "prio": 0, "policy": "accept", "dev": ["eth0", "eth1"]}"#,
+    )
+    .unwrap();
+    assert_eq!(chain.dev, vec!["eth0", "eth1"]);
+
+    let set: NftSet = serde_json::from_str(
+        r#"{"family": "inet", "name": "allowed", "table": "fractal", "type": "ipv4_addr", "flags": "interval"}"#,
+    )
+    .unwrap();
+    assert_eq!(set.flags, vec!["interval"]);
+    let set: NftSet = serde_json::from_str(
+        r#"{"family": "inet", "name": "allowed", "table": "fractal", "type": "ipv4_addr", "flags": ["constant", "interval"]}"#,
+    )
+    .unwrap();
+    assert_eq!(set.flags, vec!["constant", "interval"]);
+}
+
+#[test]
+fn test_nft_batch() {
+    let set = NftSet::new(NftFamily::Inet, "fractal", "allowed", "ipv4_addr".into())
+        .with_flags(&["interval"]);
+    let mut batch = NftBatch::new();
+    batch
+        .push(NftCommand::Add(NftObject::Table(NftTable::new(
+            NftFamily::Inet,
+            "fractal",
+        ))))
+        .replace_elements(&set, vec![nft_element_net(&"10.1.0.0/16".parse().unwrap())]);
+    assert_eq!(
+        batch.to_json(),
+        json!({"nftables": [
+            {"add": {"table": {"family": "inet", "name": "fractal"}}},
+            {"add": {"set": {"family": "inet", "table": "fractal", "name": "allowed", "type": "ipv4_addr", "flags": ["interval"]}}},
+            {"flush": {"set": {"family": "inet", "table": "fractal", "name": "allowed", "type": "ipv4_addr", "flags": ["interval"]}}},
+            {"add": {"element": {"family": "inet", "table": "fractal", "name": "allowed", "elem": [{"prefix": {"addr": "10.1.0.0", "len": 16}}]}}},
+        ]})
+    );
+}
@@ -0,0 +1,579 @@
+use crate::{netns_command, IntoNetnsRef, NetnsRef, KILL_PATH, NGINX_PATH, NGINX_PID_PATH};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Number of times [`nginx_stop`] checks whether nginx exited.
+const NGINX_STOP_ATTEMPTS: usize = 50;
+
+/// Interval at which [`nginx_stop`] checks whether nginx exited.
+const NGINX_STOP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of times [`nginx_deploy`] checks whether nginx started new workers.
+const NGINX_RELOAD_ATTEMPTS: usize = 50;
+
+/// Interval at which [`nginx_deploy`] checks whether nginx started new workers.
+const NGINX_RELOAD_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Message printed by `nginx -t`, with the location in the configuration it refers
+/// to if nginx reported one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NginxDiagnostic {
+    /// Severity such as `emerg` or `warn`.
+    pub level: String,
+    pub message: String,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+}
+
+impl NginxDiagnostic {
+    /// Parse a line such as `nginx: [emerg] unknown directive "foo" in
+    /// /etc/nginx/nginx.conf:12`. Returns `None` for lines without a severity.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let line = line.strip_prefix("nginx: ").unwrap_or(line);
+        let (level, message) = line.strip_prefix('[')?.split_once("] ")?;
+        let location = message.rsplit_once(" in ").and_then(|(message, location)| {
+            let (file, line) = location.rsplit_once(':')?;
+            Some((message, file, line.parse().ok()?))
+        });
+        let diagnostic = match location {
+            Some((message, file, line)) => NginxDiagnostic {
+                level: level.to_string(),
+                message: message.to_string(),
+                file: Some(file.into()),
+                line: Some(line),
+            },
+            None => NginxDiagnostic {
+                level: level.to_string(),
+                message: message.to_string(),
+                file: None,
+                line: None,
+            },
+        };
+        Some(diagnostic)
+    }
+
+    /// Whether this diagnostic makes the configuration test fail.
+    pub fn is_error(&self) -> bool {
+        matches!(self.level.as_str(), "emerg" | "alert" | "crit" | "error")
+    }
+}
+
+impl fmt::Display for NginxDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.level, self.message)?;
+        if let (Some(file), Some(line)) = (&self.file, self.line) {
+            write!(f, " in {}:{line}", file.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when nginx rejects its configuration. It is wrapped in the
+/// `anyhow::Error` returned by [`nginx_test`] and can be recovered with
+/// `downcast_ref` to inspect the diagnostics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NginxConfigError {
+    pub diagnostics: Vec<NginxDiagnostic>,
+}
+
+impl fmt::Display for NginxConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid nginx configuration")?;
+        for diagnostic in self.diagnostics.iter().filter(|d| d.is_error()) {
+            write!(f, ": {diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NginxConfigError {}
+
+/// Parse the output of `nginx -t`.
+fn nginx_diagnostics(output: &str) -> Vec<NginxDiagnostic> {
+    output.lines().filter_map(NginxDiagnostic::parse).collect()
+}
+
+/// Test the nginx configuration, or the configuration at `config_path` if given.
+/// Returns the warnings nginx printed if the configuration is valid, and a
+/// [`NginxConfigError`] if it is not.
+pub async fn nginx_test(config_path: Option<&Path>) -> Result<Vec<NginxDiagnostic>> {
+    nginx_test_in(None, config_path).await
+}
+
+/// Test the nginx configuration like [`nginx_test`], inside a network namespace. Named
+/// namespaces see their own files from `/etc/netns/<netns>` and resolve names with
+/// their own `resolv.conf`, which can make the outcome differ from the initial one.
+pub async fn nginx_test_in(
+    netns: impl IntoNetnsRef,
+    config_path: Option<&Path>,
+) -> Result<Vec<NginxDiagnostic>> {
+    let netns = &netns.into_netns_ref();
+    let mut command = netns_command(netns, NGINX_PATH);
+    command.arg("-t");
+    if let Some(config_path) = config_path {
+        command.arg("-c").arg(config_path);
+    }
+    let output = command.output().await?;
+    let diagnostics = nginx_diagnostics(&String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        return Err(NginxConfigError { diagnostics }.into());
+    }
+    Ok(diagnostics)
+}
+
+/// Start nginx with a configuration in a network namespace, writing the PID of its
+/// master process to `pid_file`. The configuration must not set `pid` itself. This
+/// allows running one nginx per namespace, listening on that namespace's addresses.
+pub async fn nginx_start(netns: impl IntoNetnsRef, config: &Path, pid_file: &Path) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("nginx start {} in {netns:?}", config.display());
+    let output = netns_command(netns, NGINX_PATH)
+        .arg("-c")
+        .arg(config)
+        .arg("-g")
+        .arg(format!("pid {};", pid_file.display()))
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error starting nginx in {netns:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Send a signal such as `reload` or `quit`, as accepted by `nginx -s`, to nginx.
+/// Without a PID file, `nginx -s` finds the master process through the PID file set in
+/// the configuration at `config_path`, or in the default configuration.
+async fn nginx_signal(
+    netns: &Option<NetnsRef>,
+    pid_file: Option<&Path>,
+    config_path: Option<&Path>,
+    signal: &str,
+) -> Result<()> {
+    let status = match pid_file {
+        Some(pid_file) => {
+            let pid = nginx_pid(pid_file)
+                .await?
+                .ok_or_else(|| anyhow!("nginx from {} is not running", pid_file.display()))?;
+            let kill_signal = match signal {
+                "reload" => "-HUP",
+                "quit" => "-QUIT",
+                "stop" => "-TERM",
+                "reopen" => "-USR1",
+                other => return Err(anyhow!("Unknown nginx signal {other}")),
+            };
+            Command::new(KILL_PATH)
+                .arg(kill_signal)
+                .arg(pid.to_string())
+                .status()
+                .await?
+        }
+        None => {
+            let mut command = netns_command(netns, NGINX_PATH);
+            if let Some(config_path) = config_path {
+                command.arg("-c").arg(config_path);
+            }
+            command.arg("-s").arg(signal).status().await?
+        }
+    };
+    if !status.success() {
+        return Err(anyhow!("Error sending {signal} to nginx in {netns:?}"));
+    }
+    Ok(())
+}
+
+/// Reload the configuration of nginx. With a PID file, the master process it names
+/// is signalled, otherwise `nginx -s reload` is run in the namespace, which uses the
+/// PID file of the default configuration.
+pub async fn nginx_reload(netns: impl IntoNetnsRef, pid_file: Option<&Path>) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("nginx reload in {netns:?}");
+    nginx_signal(netns, pid_file, None, "reload")
+        .await
+        .context("Error reloading nginx")
+}
+
+/// Shut nginx down gracefully and wait for its master process to exit.
+pub async fn nginx_stop(netns: impl IntoNetnsRef, pid_file: Option<&Path>) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("nginx stop in {netns:?}");
+    let pid_file = pid_file.unwrap_or_else(|| Path::new(NGINX_PID_PATH));
+    let pid = match nginx_pid(pid_file).await? {
+        Some(pid) => pid,
+        None => return Ok(()),
+    };
+    nginx_signal(netns, Some(pid_file), None, "quit").await?;
+    for _ in 0..NGINX_STOP_ATTEMPTS {
+        if !Path::new(&format!("/proc/{pid}")).exists() {
+            return Ok(());
+        }
+        tokio::time::sleep(NGINX_STOP_INTERVAL).await;
+    }
+    Err(anyhow!("nginx with PID {pid} did not stop"))
+}
+
+/// Test the configuration and reload nginx only if it is valid, since nginx keeps
+/// running with the old configuration when a reload fails. The nginx found through the
+/// PID file of the configuration at `config_path` is reloaded, like
+/// `nginx -c <config_path> -s reload` does.
+pub async fn nginx_reload_checked(config_path: Option<&Path>) -> Result<()> {
+    for warning in nginx_test(config_path).await? {
+        warn!("nginx: {warning}");
+    }
+    info!("nginx reload");
+    nginx_signal(&None, None, config_path, "reload")
+        .await
+        .context("Error reloading nginx")
+}
+
+/// Configuration file to deploy. Files without contents are removed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NginxConfigFile {
+    pub path: PathBuf,
+    pub contents: Option<String>,
+}
+
+impl NginxConfigFile {
+    pub fn new(path: &Path, contents: &str) -> Self {
+        NginxConfigFile {
+            path: path.to_path_buf(),
+            contents: Some(contents.to_string()),
+        }
+    }
+
+    /// File which is removed when deploying.
+    pub fn removed(path: &Path) -> Self {
+        NginxConfigFile {
+            path: path.to_path_buf(),
+            contents: None,
+        }
+    }
+}
+
+/// Write a file by writing a temporary file next to it and renaming it into place, so
+/// that readers see either the old or the new contents.
+pub(crate) async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid file path {}", path.display()))?;
+    let temporary = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    let permissions = match tokio::fs::metadata(path).await {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error).with_context(|| format!("Reading {}", path.display())),
+    };
+    let mut file = tokio::fs::File::create(&temporary)
+        .await
+        .with_context(|| format!("Writing {}", temporary.display()))?;
+    file.write_all(contents)
+        .await
+        .with_context(|| format!("Writing {}", temporary.display()))?;
+    // keep the mode of the file being replaced
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions).await?;
+    }
+    file.sync_all()
+        .await
+        .with_context(|| format!("Writing {}", temporary.display()))?;
+    drop(file);
+    tokio::fs::rename(&temporary, path)
+        .await
+        .with_context(|| format!("Renaming {}", temporary.display()))?;
+    Ok(())
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+            Err(error).with_context(|| format!("Removing {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Previous contents of deployed configuration files, see [`nginx_stage`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NginxBackup {
+    pub files: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+impl NginxBackup {
+    /// Put the previous contents back, removing files which did not exist before.
+    pub async fn restore(&self) -> Result<()> {
+        for (path, contents) in self.files.iter().rev() {
+            match contents {
+                Some(contents) => write_atomic(path, contents).await?,
+                None => remove_if_exists(path).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write configuration files without reloading nginx, returning the previous
+/// contents. If writing any of the files fails, the files already written are
+/// restored.
+pub async fn nginx_stage(files: &[NginxConfigFile]) -> Result<NginxBackup> {
+    let mut backup = NginxBackup { files: vec![] };
+    for file in files {
+        let previous = match tokio::fs::read(&file.path).await {
+            Ok(contents) => Some(contents),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+            Err(error) => {
+                backup.restore().await?;
+                return Err(error).with_context(|| format!("Reading {}", file.path.display()));
+            }
+        };
+        backup.files.push((file.path.clone(), previous));
+        let result = match &file.contents {
+            Some(contents) => write_atomic(&file.path, contents.as_bytes()).await,
+            None => remove_if_exists(&file.path).await,
+        };
+        if let Err(error) = result {
+            backup.restore().await?;
+            return Err(error);
+        }
+    }
+    Ok(backup)
+}
+
+/// PID of the nginx master process named in `pid_file`, if it is running. A stale PID
+/// file, or one naming a process that is not nginx, counts as not running.
+pub async fn nginx_pid(pid_file: &Path) -> Result<Option<u32>> {
+    let pid = match tokio::fs::read_to_string(pid_file).await {
+        Ok(pid) => pid,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error).with_context(|| format!("Reading {}", pid_file.display())),
+    };
+    let pid: u32 = pid
+        .trim()
+        .parse()
+        .with_context(|| format!("Parsing PID in {}", pid_file.display()))?;
+    match tokio::fs::read_to_string(format!("/proc/{pid}/comm")).await {
+        Ok(comm) if comm.trim() == "nginx" => Ok(Some(pid)),
+        _ => Ok(None),
+    }
+}
+
+/// Check if the nginx master process whose PID is in `pid_file` is running.
+pub async fn nginx_running(pid_file: &Path) -> Result<bool> {
+    Ok(nginx_pid(pid_file).await?.is_some())
+}
+
+/// Parent PID in the contents of `/proc/<pid>/stat`. The command name in parentheses
+/// may contain spaces and parentheses itself, the fields follow the last one.
+fn proc_stat_ppid(stat: &str) -> Option<u32> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// PIDs of the child processes of `pid`, for the nginx master process these are its
+/// workers and cache processes.
+async fn process_children(pid: u32) -> Result<BTreeSet<u32>> {
+    let mut children = BTreeSet::new();
+    let mut entries = tokio::fs::read_dir("/proc").await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let child = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(child) => child,
+            None => continue,
+        };
+        // processes may exit while iterating
+        if let Ok(stat) = tokio::fs::read_to_string(entry.path().join("stat")).await {
+            if proc_stat_ppid(&stat) == Some(pid) {
+                children.insert(child);
+            }
+        }
+    }
+    Ok(children)
+}
+
+/// Wait for the nginx master process `pid` to start a worker which is not in `workers`.
+/// nginx only replaces its workers if the reload succeeded, when applying the new
+/// configuration fails it logs the error and keeps the old workers running.
+async fn nginx_reload_wait(pid_file: &Path, pid: u32, workers: &BTreeSet<u32>) -> Result<()> {
+    for _ in 0..NGINX_RELOAD_ATTEMPTS {
+        if nginx_pid(pid_file).await? != Some(pid) {
+            return Err(anyhow!("nginx is not running after reload"));
+        }
+        if process_children(pid)
+            .await?
+            .iter()
+            .any(|child| !workers.contains(child))
+        {
+            return Ok(());
+        }
+        tokio::time::sleep(NGINX_RELOAD_INTERVAL).await;
+    }
+    Err(anyhow!(
+        "nginx with PID {pid} did not start new workers, check its error log"
+    ))
+}
+
+/// Deploy configuration files: write them, test the configuration, reload nginx and
+/// wait for it to start workers with the new configuration. If any step fails, the
+/// previous files are put back, and nginx is reloaded again if it already picked up the
+/// new ones.
+///
+/// The nginx in `netns` whose master process is named in `pid_file` is reloaded, see
+/// [`nginx_reload`]. Without a PID file, the default one is used. The configuration is
+/// tested inside `netns` as well.
+pub async fn nginx_deploy(
+    netns: impl IntoNetnsRef,
+    files: &[NginxConfigFile],
+    config_path: Option<&Path>,
+    pid_file: Option<&Path>,
+) -> Result<()> {
+    let netns = &netns.into_netns_ref();
+    info!("nginx_deploy({netns:?}, {})", files.len());
+    let running_pid_file = pid_file.unwrap_or_else(|| Path::new(NGINX_PID_PATH));
+    let pid = nginx_pid(running_pid_file)
+        .await?
+        .ok_or_else(|| anyhow!("nginx from {} is not running", running_pid_file.display()))?;
+    let workers = process_children(pid).await?;
+    let backup = nginx_stage(files).await?;
+    if let Err(error) = nginx_test_in(netns, config_path).await {
+        backup.restore().await?;
+        return Err(error);
+    }
+    let result = match nginx_reload(netns, pid_file).await {
+        Ok(()) => nginx_reload_wait(running_pid_file, pid, &workers).await,
+        Err(error) => Err(error),
+    };
+    if result.is_ok() {
+        return result;
+    }
+    warn!("Error deploying nginx configuration, rolling back");
+    backup.restore().await?;
+    if let Err(error) = nginx_reload(netns, pid_file).await {
+        warn!("Error reloading nginx with previous configuration: {error}");
+    }
+    result
+}
+
+#[test]
+fn test_nginx_diagnostics() {
+    let diagnostics = nginx_diagnostics(
+        "nginx: [warn] the \"ssl\" directive is deprecated in /etc/nginx/sites-enabled/a.conf:3\n\
+         nginx: [emerg] unknown directive \"proxy_pas\" in /etc/nginx/sites-enabled/tenant.conf:12\n\
+         nginx: configuration file /etc/nginx/nginx.conf test failed\n",
+    );
+    assert_eq!(
+        diagnostics,
+        vec![
+            NginxDiagnostic {
+                level: "warn".to_string(),
+                message: "the \"ssl\" directive is deprecated".to_string(),
+                file: Some("/etc/nginx/sites-enabled/a.conf".into()),
+                line: Some(3),
+            },
+            NginxDiagnostic {
+                level: "emerg".to_string(),
+                message: "unknown directive \"proxy_pas\"".to_string(),
+                file: Some("/etc/nginx/sites-enabled/tenant.conf".into()),
+                line: Some(12),
+            },
+        ]
+    );
+    assert!(!diagnostics[0].is_error());
+    assert!(diagnostics[1].is_error());
+
+    let diagnostic =
+        NginxDiagnostic::parse("nginx: [emerg] bind() to 0.0.0.0:80 failed (98: Address in use)")
+            .unwrap();
+    assert_eq!(diagnostic.file, None);
+    assert_eq!(diagnostic.line, None);
+
+    let error: anyhow::Error = NginxConfigError { diagnostics }.into();
+    assert_eq!(
+        error.to_string(),
+        "Invalid nginx configuration: [emerg] unknown directive \"proxy_pas\" in /etc/nginx/sites-enabled/tenant.conf:12"
+    );
+    assert_eq!(
+        error
+            .downcast_ref::<NginxConfigError>()
+            .unwrap()
+            .diagnostics
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn test_proc_stat_ppid() {
+    assert_eq!(
+        proc_stat_ppid("1271 (nginx) S 1162 1162 1162 0 -1 4194624 220 0 0 0"),
+        Some(1162)
+    );
+    assert_eq!(
+        proc_stat_ppid("42 (a) b (c)) R 7 42 42 0 -1 4194560 10 0 0 0"),
+        Some(7)
+    );
+    assert_eq!(proc_stat_ppid("42 (nginx"), None);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_process_children() {
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+    let pid = child.id().unwrap();
+    let children = process_children(std::process::id()).await.unwrap();
+    assert!(children.contains(&pid));
+    child.kill().await.unwrap();
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_nginx_stage_restore() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = std::env::temp_dir().join(format!("nginx-stage-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    let existing = dir.join("existing.conf");
+    let removed = dir.join("removed.conf");
+    let added = dir.join("added.conf");
+    tokio::fs::write(&existing, "old").await.unwrap();
+    tokio::fs::write(&removed, "gone").await.unwrap();
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    std::fs::set_permissions(&existing, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+    let backup = nginx_stage(&[
+        NginxConfigFile::new(&existing, "new"),
+        NginxConfigFile::removed(&removed),
+        NginxConfigFile::new(&added, "added"),
+    ])
+    .await
+    .unwrap();
+    assert_eq!(tokio::fs::read_to_string(&existing).await.unwrap(), "new");
+    assert_eq!(mode(&existing), 0o640);
+    assert!(!removed.exists());
+    assert_eq!(tokio::fs::read_to_string(&added).await.unwrap(), "added");
+
+    backup.restore().await.unwrap();
+    assert_eq!(tokio::fs::read_to_string(&existing).await.unwrap(), "old");
+    assert_eq!(tokio::fs::read_to_string(&removed).await.unwrap(), "gone");
+    assert!(!added.exists());
+
+    // failing to write a file restores the ones already written
+    let result = nginx_stage(&[
+        NginxConfigFile::new(&existing, "new"),
+        NginxConfigFile::new(&dir.join("missing/file.conf"), "new"),
+    ])
+    .await;
+    assert!(result.is_err());
+    assert_eq!(tokio::fs::read_to_string(&existing).await.unwrap(), "old");
+
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
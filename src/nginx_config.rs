@@ -0,0 +1,438 @@
+use crate::{NginxConfigFile, Protocol};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Quote a value for an nginx configuration file if needed. Values containing `$`
+/// are rejected, since nginx would expand them as variables even inside quotes.
+fn quote(value: &str) -> Result<String> {
+    if value.contains('$') {
+        return Err(anyhow!("Invalid nginx value {value:?}"));
+    }
+    let plain = |c: char| c.is_ascii_alphanumeric() || "._-:/*~[]".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        return Ok(value.to_string());
+    }
+    Ok(format!(
+        "\"{}\"",
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// Turn a name into an identifier usable for upstreams and variables. Names with
+/// other characters than letters, digits and `_` get a hash of the name appended, so
+/// that names like `a-b` and `a.b` don't end up with the same identifier.
+fn identifier(name: &str) -> String {
+    let identifier: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if identifier == name {
+        return identifier;
+    }
+    // FNV-1a, which is stable across releases unlike the hasher of the standard library
+    let hash = name.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    format!("{identifier}_{hash:08x}")
+}
+
+fn write_listen(
+    output: &mut String,
+    addresses: &[IpAddr],
+    port: u16,
+    suffix: &str,
+) -> std::fmt::Result {
+    // a bare port only listens on IPv4
+    if addresses.is_empty() {
+        writeln!(output, "    listen {port}{suffix};")?;
+        writeln!(output, "    listen [::]:{port}{suffix};")?;
+    }
+    for address in addresses {
+        writeln!(
+            output,
+            "    listen {}{suffix};",
+            SocketAddr::new(*address, port)
+        )?;
+    }
+    Ok(())
+}
+
+fn write_upstream(output: &mut String, name: &str, servers: &[SocketAddr]) -> Result<()> {
+    if servers.is_empty() {
+        return Err(anyhow!("Upstream {name} has no servers"));
+    }
+    writeln!(output, "upstream {name} {{")?;
+    for server in servers {
+        writeln!(output, "    server {server};")?;
+    }
+    writeln!(output, "}}")?;
+    writeln!(output)?;
+    Ok(())
+}
+
+/// Certificate and key used to terminate TLS.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NginxTls {
+    pub certificate: PathBuf,
+    pub certificate_key: PathBuf,
+}
+
+/// HTTP reverse proxy for a service, rendered as an `upstream` and a `server` block
+/// for the `http` context.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NginxHttpServer {
+    /// Name of the service, used to name the upstream.
+    pub name: String,
+    pub server_names: Vec<String>,
+    /// Addresses to listen on, all addresses if empty.
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub tls: Option<NginxTls>,
+    pub upstreams: Vec<SocketAddr>,
+    /// Whether to pass on WebSocket upgrades.
+    pub websocket: bool,
+}
+
+impl NginxHttpServer {
+    /// Create a server listening on port 80 which proxies to `upstreams`.
+    pub fn new(name: &str, server_names: &[&str], upstreams: &[SocketAddr]) -> Self {
+        NginxHttpServer {
+            name: name.to_string(),
+            server_names: server_names.iter().map(|name| name.to_string()).collect(),
+            addresses: vec![],
+            port: 80,
+            tls: None,
+            upstreams: upstreams.to_vec(),
+            websocket: false,
+        }
+    }
+
+    /// Terminate TLS with the given certificate. This switches to port 443.
+    pub fn with_tls(mut self, certificate: &Path, certificate_key: &Path) -> Self {
+        self.tls = Some(NginxTls {
+            certificate: certificate.to_path_buf(),
+            certificate_key: certificate_key.to_path_buf(),
+        });
+        self.port = 443;
+        self
+    }
+
+    /// Only listen on the given addresses, such as a WireGuard address.
+    pub fn with_addresses(mut self, addresses: &[IpAddr]) -> Self {
+        self.addresses = addresses.to_vec();
+        self
+    }
+
+    /// Pass on WebSocket upgrades.
+    pub fn with_websocket(mut self) -> Self {
+        self.websocket = true;
+        self
+    }
+
+    /// Render the configuration for the `http` context.
+    pub fn render(&self) -> Result<String> {
+        let upstream = identifier(&self.name);
+        let mut output = String::new();
+        write_upstream(&mut output, &upstream, &self.upstreams)?;
+        // only ask for a connection upgrade if the client did
+        if self.websocket {
+            writeln!(output, "map $http_upgrade ${upstream}_connection {{")?;
+            writeln!(output, "    default upgrade;")?;
+            writeln!(output, "    \"\" close;")?;
+            writeln!(output, "}}")?;
+            writeln!(output)?;
+        }
+        writeln!(output, "server {{")?;
+        let suffix = if self.tls.is_some() { " ssl" } else { "" };
+        write_listen(&mut output, &self.addresses, self.port, suffix)?;
+        if !self.server_names.is_empty() {
+            let names = self
+                .server_names
+                .iter()
+                .map(|name| quote(name))
+                .collect::<Result<Vec<_>>>()?;
+            writeln!(output, "    server_name {};", names.join(" "))?;
+        }
+        if let Some(tls) = &self.tls {
+            let path = |path: &Path| quote(&path.to_string_lossy());
+            writeln!(output, "    ssl_certificate {};", path(&tls.certificate)?)?;
+            writeln!(
+                output,
+                "    ssl_certificate_key {};",
+                path(&tls.certificate_key)?
+            )?;
+        }
+        writeln!(output)?;
+        writeln!(output, "    location / {{")?;
+        writeln!(output, "        proxy_pass http://{upstream};")?;
+        writeln!(output, "        proxy_http_version 1.1;")?;
+        writeln!(output, "        proxy_set_header Host $host;")?;
+        writeln!(
+            output,
+            "        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;"
+        )?;
+        writeln!(
+            output,
+            "        proxy_set_header X-Forwarded-Proto $scheme;"
+        )?;
+        if self.websocket {
+            writeln!(output, "        proxy_set_header Upgrade $http_upgrade;")?;
+            writeln!(
+                output,
+                "        proxy_set_header Connection ${upstream}_connection;"
+            )?;
+        }
+        writeln!(output, "    }}")?;
+        writeln!(output, "}}")?;
+        Ok(output)
+    }
+
+    /// Render the configuration as a file to pass to [`nginx_deploy`](crate::nginx_deploy).
+    pub fn config_file(&self, path: &Path) -> Result<NginxConfigFile> {
+        Ok(NginxConfigFile::new(path, &self.render()?))
+    }
+}
+
+/// Route of a stream server, selected by the server name the client sends in the
+/// TLS handshake.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NginxStreamRoute {
+    pub server_name: String,
+    pub upstreams: Vec<SocketAddr>,
+}
+
+/// TCP or UDP passthrough, rendered for the `stream` context. With routes, TLS
+/// connections are passed to the upstream matching their SNI server name using
+/// `ssl_preread`, without terminating TLS.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NginxStreamServer {
+    /// Name of the service, used to name upstreams and variables.
+    pub name: String,
+    /// Addresses to listen on, all addresses if empty.
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub routes: Vec<NginxStreamRoute>,
+    /// Upstreams for connections that match no route.
+    pub default: Vec<SocketAddr>,
+}
+
+impl NginxStreamServer {
+    /// Create a server passing connections on `port` to `upstreams`.
+    pub fn new(name: &str, protocol: Protocol, port: u16, upstreams: &[SocketAddr]) -> Self {
+        NginxStreamServer {
+            name: name.to_string(),
+            addresses: vec![],
+            port,
+            protocol,
+            routes: vec![],
+            default: upstreams.to_vec(),
+        }
+    }
+
+    /// Only listen on the given addresses, such as a WireGuard address.
+    pub fn with_addresses(mut self, addresses: &[IpAddr]) -> Self {
+        self.addresses = addresses.to_vec();
+        self
+    }
+
+    /// Pass TLS connections for `server_name` to `upstreams`.
+    pub fn with_route(mut self, server_name: &str, upstreams: &[SocketAddr]) -> Self {
+        self.routes.push(NginxStreamRoute {
+            server_name: server_name.to_string(),
+            upstreams: upstreams.to_vec(),
+        });
+        self
+    }
+
+    /// Render the configuration for the `stream` context.
+    pub fn render(&self) -> Result<String> {
+        let name = identifier(&self.name);
+        if !self.routes.is_empty() && self.protocol != Protocol::Tcp {
+            return Err(anyhow!("SNI routes of {} need TCP", self.name));
+        }
+        let mut output = String::new();
+        let proxy_pass = if self.routes.is_empty() {
+            write_upstream(&mut output, &name, &self.default)?;
+            name
+        } else {
+            let mut map = String::new();
+            for (index, route) in self.routes.iter().enumerate() {
+                let upstream = format!("{name}_{index}");
+                write_upstream(&mut output, &upstream, &route.upstreams)?;
+                writeln!(map, "    {} {upstream};", quote(&route.server_name)?)?;
+            }
+            if !self.default.is_empty() {
+                let upstream = format!("{name}_default");
+                write_upstream(&mut output, &upstream, &self.default)?;
+                writeln!(map, "    default {upstream};")?;
+            }
+            writeln!(output, "map $ssl_preread_server_name ${name}_upstream {{")?;
+            // needed for wildcard server names such as `*.example.com`
+            writeln!(output, "    hostnames;")?;
+            write!(output, "{map}")?;
+            writeln!(output, "}}")?;
+            writeln!(output)?;
+            format!("${name}_upstream")
+        };
+        writeln!(output, "server {{")?;
+        let suffix = match self.protocol {
+            Protocol::Tcp => "",
+            Protocol::Udp => " udp",
+        };
+        write_listen(&mut output, &self.addresses, self.port, suffix)?;
+        writeln!(output, "    proxy_pass {proxy_pass};")?;
+        if !self.routes.is_empty() {
+            writeln!(output, "    ssl_preread on;")?;
+        }
+        writeln!(output, "}}")?;
+        Ok(output)
+    }
+
+    /// Render the configuration as a file to pass to [`nginx_deploy`](crate::nginx_deploy).
+    /// The file needs to be included from the `stream` context.
+    pub fn config_file(&self, path: &Path) -> Result<NginxConfigFile> {
+        Ok(NginxConfigFile::new(path, &self.render()?))
+    }
+}
+
+#[test]
+fn test_nginx_http_server_render() {
+    let server = NginxHttpServer::new(
+        "tenant-a.app",
+        &["app.example.com", "*.app.example.com"],
+        &[
+            "10.80.0.2:8080".parse().unwrap(),
+            "[fd00::2]:8080".parse().unwrap(),
+        ],
+    )
+    .with_tls(
+        Path::new("/etc/ssl/app example.pem"),
+        Path::new("/etc/ssl/app.key"),
+    )
+    .with_addresses(&["10.80.0.1".parse().unwrap(), "fd00::1".parse().unwrap()])
+    .with_websocket();
+    assert_eq!(
+        server.render().unwrap(),
+        r#"upstream tenant_a_app_6ca6123a {
+    server 10.80.0.2:8080;
+    server [fd00::2]:8080;
+}
+
+map $http_upgrade $tenant_a_app_6ca6123a_connection {
+    default upgrade;
+    "" close;
+}
+
+server {
+    listen 10.80.0.1:443 ssl;
+    listen [fd00::1]:443 ssl;
+    server_name app.example.com *.app.example.com;
+    ssl_certificate "/etc/ssl/app example.pem";
+    ssl_certificate_key /etc/ssl/app.key;
+
+    location / {
+        proxy_pass http://tenant_a_app_6ca6123a;
+        proxy_http_version 1.1;
+        proxy_set_header Host $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header X-Forwarded-Proto $scheme;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection $tenant_a_app_6ca6123a_connection;
+    }
+}
+"#
+    );
+
+    let server = NginxHttpServer::new("plain", &[], &["10.0.0.2:80".parse().unwrap()]);
+    let rendered = server.render().unwrap();
+    assert!(rendered.contains("upstream plain {\n"));
+    assert!(rendered.contains("    listen 80;\n    listen [::]:80;\n"));
+    assert!(!rendered.contains("map $http_upgrade"));
+
+    // names differing only in replaced characters get different identifiers
+    assert_ne!(identifier("tenant-a.app"), identifier("tenant.a-app"));
+    assert_ne!(identifier("tenant-a"), identifier("tenant_a"));
+
+    // injection attempts are quoted or rejected
+    let server = NginxHttpServer::new(
+        "evil",
+        &["a.com; return 200"],
+        &["10.0.0.2:80".parse().unwrap()],
+    );
+    assert!(server
+        .render()
+        .unwrap()
+        .contains("server_name \"a.com; return 200\";"));
+    let server = NginxHttpServer::new("evil", &["$host"], &["10.0.0.2:80".parse().unwrap()]);
+    assert!(server.render().is_err());
+    assert!(NginxHttpServer::new("empty", &[], &[]).render().is_err());
+}
+
+#[test]
+fn test_nginx_stream_server_render() {
+    let server = NginxStreamServer::new(
+        "tls",
+        Protocol::Tcp,
+        443,
+        &["10.80.0.9:443".parse().unwrap()],
+    )
+    .with_route("a.example.com", &["10.80.0.2:443".parse().unwrap()])
+    .with_route("*.b.example.com", &["10.80.0.3:443".parse().unwrap()]);
+    assert_eq!(
+        server.render().unwrap(),
+        r#"upstream tls_0 {
+    server 10.80.0.2:443;
+}
+
+upstream tls_1 {
+    server 10.80.0.3:443;
+}
+
+upstream tls_default {
+    server 10.80.0.9:443;
+}
+
+map $ssl_preread_server_name $tls_upstream {
+    hostnames;
+    a.example.com tls_0;
+    *.b.example.com tls_1;
+    default tls_default;
+}
+
+server {
+    listen 443;
+    listen [::]:443;
+    proxy_pass $tls_upstream;
+    ssl_preread on;
+}
+"#
+    );
+
+    let server = NginxStreamServer::new(
+        "wireguard",
+        Protocol::Udp,
+        51820,
+        &["10.80.0.2:51820".parse().unwrap()],
+    )
+    .with_addresses(&["192.0.2.1".parse().unwrap()]);
+    assert_eq!(
+        server.render().unwrap(),
+        r#"upstream wireguard {
+    server 10.80.0.2:51820;
+}
+
+server {
+    listen 192.0.2.1:51820 udp;
+    proxy_pass wireguard;
+}
+"#
+    );
+    assert!(server
+        .with_route("a.example.com", &["10.80.0.2:443".parse().unwrap()])
+        .render()
+        .is_err());
+}
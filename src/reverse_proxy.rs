@@ -0,0 +1,371 @@
+use crate::nginx::write_atomic;
+use crate::{
+    netns_command, nginx_reload, nginx_running, nginx_test_in, IntoNetnsRef, NetnsRef, NginxBackup,
+    HAPROXY_PATH, NGINX_PID_PATH,
+};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+
+/// Default address of the Caddy admin API.
+pub const CADDY_ADMIN_ADDR: &str = "127.0.0.1:2019";
+
+/// Reverse proxy whose configuration can be replaced at runtime.
+pub trait ReverseProxy {
+    /// Path of the configuration file.
+    fn config_path(&self) -> &Path;
+
+    /// Write a new configuration to [`ReverseProxy::config_path`], without applying it.
+    fn write_config(&self, config: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Check that the written configuration is valid.
+    fn validate(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Apply the written configuration.
+    fn reload(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Check if the proxy is running.
+    fn status(&self) -> impl Future<Output = Result<bool>> + Send;
+}
+
+/// nginx, controlled with the `nginx_*` functions.
+#[derive(Clone, Debug)]
+pub struct Nginx {
+    pub netns: Option<NetnsRef>,
+    pub config_path: PathBuf,
+    pub pid_file: Option<PathBuf>,
+}
+
+impl Nginx {
+    pub fn new(netns: impl IntoNetnsRef, config_path: &Path, pid_file: Option<&Path>) -> Self {
+        Nginx {
+            netns: netns.into_netns_ref(),
+            config_path: config_path.to_path_buf(),
+            pid_file: pid_file.map(|pid_file| pid_file.to_path_buf()),
+        }
+    }
+}
+
+impl ReverseProxy for Nginx {
+    fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    async fn write_config(&self, config: &str) -> Result<()> {
+        write_atomic(&self.config_path, config.as_bytes()).await
+    }
+
+    async fn validate(&self) -> Result<()> {
+        nginx_test_in(&self.netns, Some(&self.config_path)).await?;
+        Ok(())
+    }
+
+    async fn reload(&self) -> Result<()> {
+        nginx_reload(&self.netns, self.pid_file.as_deref()).await
+    }
+
+    async fn status(&self) -> Result<bool> {
+        let pid_file = self
+            .pid_file
+            .as_deref()
+            .unwrap_or_else(|| Path::new(NGINX_PID_PATH));
+        nginx_running(pid_file).await
+    }
+}
+
+/// HAProxy running in master-worker mode, reloaded through its master socket
+/// (`-S` option).
+#[derive(Clone, Debug)]
+pub struct Haproxy {
+    pub netns: Option<NetnsRef>,
+    pub config_path: PathBuf,
+    pub master_socket: PathBuf,
+}
+
+impl Haproxy {
+    pub fn new(netns: impl IntoNetnsRef, config_path: &Path, master_socket: &Path) -> Self {
+        Haproxy {
+            netns: netns.into_netns_ref(),
+            config_path: config_path.to_path_buf(),
+            master_socket: master_socket.to_path_buf(),
+        }
+    }
+
+    /// Send a command to the master socket and return the response.
+    pub async fn master_command(&self, command: &str) -> Result<String> {
+        let mut stream = UnixStream::connect(&self.master_socket)
+            .await
+            .with_context(|| format!("Connecting to {}", self.master_socket.display()))?;
+        stream.write_all(format!("{command}\n").as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+}
+
+impl ReverseProxy for Haproxy {
+    fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    async fn write_config(&self, config: &str) -> Result<()> {
+        write_atomic(&self.config_path, config.as_bytes()).await
+    }
+
+    async fn validate(&self) -> Result<()> {
+        let output = netns_command(&self.netns, HAPROXY_PATH)
+            .arg("-c")
+            .arg("-f")
+            .arg(&self.config_path)
+            .output()
+            .await?;
+        if !output.status.success() {
+            // haproxy reports the errors on stderr, older versions on stdout
+            let message = [&output.stderr, &output.stdout]
+                .iter()
+                .map(|output| String::from_utf8_lossy(output).trim().to_string())
+                .filter(|output| !output.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(anyhow!("Invalid haproxy configuration: {message}"));
+        }
+        Ok(())
+    }
+
+    async fn reload(&self) -> Result<()> {
+        info!("haproxy reload {}", self.master_socket.display());
+        let response = self.master_command("reload").await?;
+        // newer versions report the outcome of the reload
+        if response.contains("Success=0") {
+            return Err(anyhow!("Error reloading haproxy: {}", response.trim()));
+        }
+        Ok(())
+    }
+
+    /// Running if the master reports at least one current worker.
+    async fn status(&self) -> Result<bool> {
+        match self.master_command("show proc").await {
+            Ok(response) => Ok(!haproxy_workers(&response).is_empty()),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+/// PIDs of the current workers in the reply to `show proc` on the master socket. Workers
+/// of previous configurations which are still finishing their connections are listed
+/// after `# old workers` and are not included.
+fn haproxy_workers(response: &str) -> Vec<u32> {
+    let mut workers = vec![];
+    let mut section = "";
+    for line in response.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('#') {
+            if !name.starts_with('<') {
+                section = name.trim();
+            }
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        if let (Some(pid), Some("worker")) = (fields.next(), fields.next()) {
+            if section == "workers" {
+                workers.extend(pid.parse::<u32>().ok());
+            }
+        }
+    }
+    workers
+}
+
+#[test]
+fn test_haproxy_workers() {
+    let response = "#<PID>          <type>          <reloads>       <uptime>        <version>\n\
+                    1162            master          1 [failed: 0]   0d00h02m07s     2.8.5\n\
+                    # workers\n\
+                    1271            worker          0               0d00h00m00s     2.8.5\n\
+                    1272            worker          0               0d00h00m00s     2.8.5\n\
+                    # old workers\n\
+                    1233            worker          1               0d00h02m07s     2.8.5\n\
+                    # programs\n\
+                    \n";
+    assert_eq!(haproxy_workers(response), vec![1271, 1272]);
+    assert_eq!(
+        haproxy_workers("#<PID> <type> <reloads> <uptime> <version>\n1162 master 0 0d00h00m01s 2.8.5\n# workers\n"),
+        Vec::<u32>::new()
+    );
+}
+
+/// Caddy, configured through its admin API with a JSON configuration. The admin API
+/// has to be reachable from the current network namespace. Validation only checks
+/// that the configuration is JSON, Caddy checks the rest when loading it and keeps
+/// the previous configuration if it is rejected.
+#[derive(Clone, Debug)]
+pub struct Caddy {
+    pub admin: SocketAddr,
+    pub config_path: PathBuf,
+}
+
+impl Caddy {
+    pub fn new(admin: SocketAddr, config_path: &Path) -> Self {
+        Caddy {
+            admin,
+            config_path: config_path.to_path_buf(),
+        }
+    }
+
+    /// Caddy with the admin API on its default address.
+    pub fn local(config_path: &Path) -> Self {
+        Caddy::new(CADDY_ADMIN_ADDR.parse().unwrap(), config_path)
+    }
+
+    /// Send a request to the admin API, returning the status code and body.
+    pub async fn admin_request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&str>,
+    ) -> Result<(u16, String)> {
+        let mut stream = TcpStream::connect(self.admin)
+            .await
+            .with_context(|| format!("Connecting to Caddy admin API at {}", self.admin))?;
+        let mut request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            self.admin
+        );
+        if let Some(body) = body {
+            request.push_str("Content-Type: application/json\r\n");
+            request.push_str(&format!("Content-Length: {}\r\n\r\n{body}", body.len()));
+        } else {
+            request.push_str("\r\n");
+        }
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow!("Invalid response from Caddy admin API"))?;
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid status line from Caddy admin API"))?;
+        Ok((status, body.to_string()))
+    }
+}
+
+impl ReverseProxy for Caddy {
+    fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    async fn write_config(&self, config: &str) -> Result<()> {
+        write_atomic(&self.config_path, config.as_bytes()).await
+    }
+
+    async fn validate(&self) -> Result<()> {
+        let config = tokio::fs::read_to_string(&self.config_path).await?;
+        serde_json::from_str::<serde_json::Value>(&config).with_context(|| {
+            format!("Invalid Caddy configuration {}", self.config_path.display())
+        })?;
+        Ok(())
+    }
+
+    async fn reload(&self) -> Result<()> {
+        info!("caddy load {}", self.config_path.display());
+        let config = tokio::fs::read_to_string(&self.config_path).await?;
+        let (status, body) = self.admin_request("POST", "/load", Some(&config)).await?;
+        if status != 200 {
+            return Err(anyhow!(
+                "Error loading Caddy configuration: {}",
+                body.trim()
+            ));
+        }
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<bool> {
+        Ok(matches!(
+            self.admin_request("GET", "/config/", None).await,
+            Ok((200, _))
+        ))
+    }
+}
+
+/// Write, validate and apply a configuration. If it is invalid, the previous
+/// configuration file is put back and nothing is applied, so a later restart of the
+/// proxy does not pick up the invalid configuration.
+pub async fn reverse_proxy_apply<P: ReverseProxy>(proxy: &P, config: &str) -> Result<()> {
+    let path = proxy.config_path();
+    let previous = match tokio::fs::read(path).await {
+        Ok(contents) => Some(contents),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error).with_context(|| format!("Reading {}", path.display())),
+    };
+    let backup = NginxBackup {
+        files: vec![(path.to_path_buf(), previous)],
+    };
+    proxy.write_config(config).await?;
+    if let Err(error) = proxy.validate().await {
+        backup.restore().await?;
+        return Err(error);
+    }
+    proxy.reload().await
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_caddy_admin() {
+    use tokio::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let mut requests = vec![];
+        for response in ["HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"; 2] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let mut length = 0;
+            // read until the headers and the announced body are complete
+            loop {
+                length += stream.read(&mut request[length..]).await.unwrap();
+                let text = String::from_utf8_lossy(&request[..length]).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let expected = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map(|length| length.parse().unwrap())
+                        .unwrap_or(0);
+                    if body.len() >= expected {
+                        requests.push(text);
+                        break;
+                    }
+                }
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    });
+
+    let config_path = std::env::temp_dir().join(format!("caddy-admin-{}.json", std::process::id()));
+    let caddy = Caddy::new(admin, &config_path);
+    reverse_proxy_apply(&caddy, r#"{"apps":{}}"#).await.unwrap();
+    assert!(caddy.status().await.unwrap());
+    assert!(caddy.write_config("{").await.is_ok());
+    assert!(caddy.validate().await.is_err());
+
+    // an invalid configuration is not left in place
+    caddy.write_config(r#"{"apps":{}}"#).await.unwrap();
+    assert!(reverse_proxy_apply(&caddy, "{").await.is_err());
+    assert_eq!(
+        tokio::fs::read_to_string(&config_path).await.unwrap(),
+        r#"{"apps":{}}"#
+    );
+
+    let requests = server.await.unwrap();
+    assert!(requests[0].starts_with("POST /load HTTP/1.1\r\n"));
+    assert!(requests[0].contains("Content-Type: application/json\r\n"));
+    assert!(requests[0].ends_with("\r\n\r\n{\"apps\":{}}"));
+    assert!(requests[1].starts_with("GET /config/ HTTP/1.1\r\n"));
+    tokio::fs::remove_file(&config_path).await.unwrap();
+}
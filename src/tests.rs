@@ -123,3 +123,343 @@ async fn test_wireguard_up() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[ignore]
+#[tokio::test]
+async fn test_wireguard_rotate_key() -> Result<(), Box<dyn Error>> {
+    let wireguard_interface = "wg73829103";
+    let netns = "kjqwehqwe";
+    netns_add(netns).await?;
+    wireguard_create(Some(netns), wireguard_interface).await?;
+
+    // rotating stores the key and applies it to the interface
+    let pubkey = wireguard_rotate_key(netns, wireguard_interface).await?;
+    let stored = wireguard_key_load(netns, wireguard_interface)
+        .await?
+        .unwrap();
+    assert_eq!(wireguard_pubkey(&stored), pubkey);
+    let stats = wireguard_stats(netns, wireguard_interface).await?;
+    assert_eq!(stats.public_key, Some(pubkey));
+
+    // rotating again produces a different key
+    assert_ne!(
+        wireguard_rotate_key(netns, wireguard_interface).await?,
+        pubkey
+    );
+
+    // clean up
+    wireguard_key_delete(netns, wireguard_interface).await?;
+    assert!(wireguard_key_load(netns, wireguard_interface)
+        .await?
+        .is_none());
+    interface_del(Some(netns), wireguard_interface).await?;
+    netns_del(netns).await?;
+
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_wireguard_up_down() -> Result<(), Box<dyn Error>> {
+    let wireguard_interface = "wg58203948";
+    let netns = "pqowieurpq";
+    netns_add(netns).await?;
+
+    let mut config = WireguardConfig::default();
+    config.interface.private_key = Some(wireguard_genkey());
+    config.interface.address = vec!["10.99.0.1/24".parse()?];
+    config.interface.mtu = Some(1300);
+    let mut peer = WireguardPeerConfig::new(wireguard_genkey().pubkey());
+    peer.allowed_ips = vec!["10.98.0.0/16".parse()?];
+    config.peers.push(peer);
+
+    // bring up and verify
+    wireguard_up(netns, wireguard_interface, &config).await?;
+    let show = interface_show(Some(netns), wireguard_interface).await?;
+    assert_eq!(show.mtu, Some(1300));
+    assert!(!show.is_down());
+    assert_eq!(
+        addr_list(Some(netns), wireguard_interface).await?,
+        config.interface.address
+    );
+
+    // take down, interface is gone
+    wireguard_down(netns, wireguard_interface, &config).await?;
+    assert!(interface_show(Some(netns), wireguard_interface)
+        .await
+        .is_err());
+
+    // failing hook rolls back the interface
+    config.interface.post_up = vec!["false".into()];
+    assert!(wireguard_up(netns, wireguard_interface, &config)
+        .await
+        .is_err());
+    assert!(interface_show(Some(netns), wireguard_interface)
+        .await
+        .is_err());
+
+    netns_del(netns).await?;
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_wireguard_create_in() -> Result<(), Box<dyn Error>> {
+    let wireguard_interface = "wg19283746";
+    let birthplace = "zmxncbvzmx";
+    let netns = "qpwoeiruty";
+    netns_add(birthplace).await?;
+    netns_add(netns).await?;
+
+    // created in birthplace, ends up in netns
+    wireguard_create_in(Some(birthplace), Some(netns), wireguard_interface).await?;
+    assert!(wireguard_exists(Some(netns), wireguard_interface).await?);
+    assert!(!wireguard_exists(Some(birthplace), wireguard_interface).await?);
+    assert!(!wireguard_exists(None, wireguard_interface).await?);
+    interface_del(Some(netns), wireguard_interface).await?;
+
+    // created directly in netns
+    wireguard_create_in(Some(netns), Some(netns), wireguard_interface).await?;
+    assert!(wireguard_exists(Some(netns), wireguard_interface).await?);
+    interface_del(Some(netns), wireguard_interface).await?;
+
+    // created in netns, moved to the current namespace
+    wireguard_create_in(Some(netns), None, wireguard_interface).await?;
+    assert!(wireguard_exists(None, wireguard_interface).await?);
+    interface_del(None, wireguard_interface).await?;
+
+    netns_del(birthplace).await?;
+    netns_del(netns).await?;
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_iptables_apply_confirm() -> Result<(), Box<dyn Error>> {
+    use std::time::Duration;
+    let netns_name = "test_iptables_apply";
+    netns_add(netns_name).await?;
+
+    let mut desired = IptablesState::default();
+    desired
+        .table_mut("filter")
+        .append_rule(IptablesRule::new("INPUT", &["-i", "lo"], "ACCEPT"));
+    let rule = desired.table("filter").unwrap().rules[0].clone();
+
+    // not confirmed in time, rolled back
+    let pending = iptables_apply_confirm(
+        Some(netns_name),
+        IpFamily::V4,
+        &desired,
+        Duration::from_millis(100),
+    )
+    .await?;
+    assert!(iptables_save_state(Some(netns_name))
+        .await?
+        .contains_rule("filter", &rule));
+    assert!(pending.wait().await?);
+    assert!(!iptables_save_state(Some(netns_name))
+        .await?
+        .contains_rule("filter", &rule));
+
+    // confirmed, kept
+    let pending = iptables_apply_confirm(
+        Some(netns_name),
+        IpFamily::V4,
+        &desired,
+        Duration::from_secs(10),
+    )
+    .await?;
+    pending.confirm().await?;
+    assert!(iptables_save_state(Some(netns_name))
+        .await?
+        .contains_rule("filter", &rule));
+
+    netns_del(netns_name).await?;
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_nginx_netns() -> Result<(), Box<dyn Error>> {
+    use std::path::Path;
+    let netns_name = "test_nginx_netns";
+    let config = Path::new("/tmp/test_nginx_netns.conf");
+    let pid_file = Path::new("/tmp/test_nginx_netns.pid");
+    netns_add(netns_name).await?;
+    tokio::fs::write(
+        config,
+        "events {}\nhttp { server { listen 127.0.0.1:8080; } }\n",
+    )
+    .await?;
+
+    // start it, now it runs
+    nginx_start(Some(netns_name), config, pid_file).await?;
+    assert!(nginx_running(pid_file).await?);
+
+    // reload keeps the same master process
+    let pid = nginx_pid(pid_file).await?;
+    nginx_reload(Some(netns_name), Some(pid_file)).await?;
+    assert_eq!(nginx_pid(pid_file).await?, pid);
+
+    // stop it, now it doesn't
+    nginx_stop(Some(netns_name), Some(pid_file)).await?;
+    assert!(!nginx_running(pid_file).await?);
+
+    netns_del(netns_name).await?;
+    tokio::fs::remove_file(config).await?;
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_netns_handle() -> Result<(), Box<dyn Error>> {
+    let netns_name = "test_netns_handle";
+
+    // dropping the handle deletes the namespace and its interfaces
+    {
+        let netns = NetworkNamespace::create(netns_name).await?;
+        let bridge = netns.bridge_add("br0").await?;
+        bridge.up().await?;
+        assert!(netns.bridge_exists("br0").await?);
+    }
+    assert!(!netns_exists(netns_name).await?);
+
+    // closing reports errors, kept namespaces survive the handle
+    let netns = NetworkNamespace::create(netns_name).await?;
+    let (outer, inner) = netns.veth_add("veth-handle", "eth0").await?;
+    assert!(netns.veth_exists(inner.name()).await?);
+    outer.close().await?;
+    assert!(!netns.veth_exists(inner.name()).await?);
+    drop(netns.with_delete_on_drop(false));
+    assert!(netns_exists(netns_name).await?);
+    NetworkNamespace::open(netns_name)
+        .with_delete_on_drop(true)
+        .close()
+        .await?;
+    assert!(!netns_exists(netns_name).await?);
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_netns_ref_pid() -> Result<(), Box<dyn Error>> {
+    let netns_name = "test_netns_ref_pid";
+    let attached = "test_netns_ref_attached";
+    let wireguard_interface = "wg82736451";
+    netns_add(netns_name).await?;
+    let mut process = netns_command(&Some(NetnsRef::Named(netns_name.to_string())), "sleep")
+        .arg("60")
+        .spawn()?;
+    let pid = process.id().unwrap();
+
+    // unnamed namespace of a process
+    wireguard_create(NetnsRef::Pid(pid), wireguard_interface).await?;
+    assert!(wireguard_exists(NetnsRef::Pid(pid), wireguard_interface).await?);
+    assert!(wireguard_exists(Some(netns_name), wireguard_interface).await?);
+
+    // attached under a name, it is the same namespace
+    netns_attach(attached, pid).await?;
+    assert!(wireguard_exists(Some(attached), wireguard_interface).await?);
+    netns_del(attached).await?;
+
+    process.kill().await?;
+    netns_del(netns_name).await?;
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_netns_ids() -> Result<(), Box<dyn Error>> {
+    let netns_name = "test_netns_ids";
+    netns_add(netns_name).await?;
+
+    // assign an id, it can be read back and resolved
+    netns_set_id(None, netns_name, 4242).await?;
+    assert_eq!(netns_get_id(None, netns_name).await?, Some(4242));
+    assert_eq!(
+        netns_ids(None).await?.get(&4242).map(String::as_str),
+        Some(netns_name)
+    );
+
+    // veth peer is resolved to its name and namespace
+    veth_add(netns_name, "veth-ids", "eth0").await?;
+    let peers = link_peers(None).await?;
+    let peer = peers.iter().find(|peer| peer.ifname == "veth-ids").unwrap();
+    assert_eq!(peer.peer_netnsid, 4242);
+    assert_eq!(peer.peer_netns.as_deref(), Some(netns_name));
+    assert_eq!(peer.peer_ifname.as_deref(), Some("eth0"));
+
+    interface_del(None, "veth-ids").await?;
+    netns_del(netns_name).await?;
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_netns_list_details() -> Result<(), Box<dyn Error>> {
+    let netns_name = "test_netns_list_details";
+    netns_add(netns_name).await?;
+
+    // live namespace has its path and inode
+    let item = netns_list()
+        .await?
+        .into_iter()
+        .find(|item| item.name == netns_name)
+        .unwrap();
+    assert!(item.alive);
+    assert_eq!(item.path, Path::new(NETNS_RUN_PATH).join(netns_name));
+    assert!(item.inode.is_some());
+
+    // unmounted, only a dangling mount point is left
+    let status = tokio::process::Command::new("umount")
+        .arg(&item.path)
+        .status()
+        .await?;
+    assert!(status.success());
+    assert!(!netns_exists(netns_name).await?);
+    let item = netns_list()
+        .await?
+        .into_iter()
+        .find(|item| item.name == netns_name)
+        .unwrap();
+    assert!(!item.alive);
+    assert_eq!(item.inode, None);
+
+    netns_del(netns_name).await?;
+    assert!(!netns_exists(netns_name).await?);
+    Ok(())
+}
+
+#[ignore]
+#[tokio::test]
+async fn test_monitor() -> Result<(), Box<dyn Error>> {
+    use tokio_stream::StreamExt;
+    let netns_name = "test_monitor";
+    netns_add(netns_name).await?;
+    let mut events = monitor(Some(netns_name), &[MonitorKind::Link, MonitorKind::Address]).await?;
+
+    // creating and removing a bridge is reported
+    bridge_add(Some(netns_name), "br0").await?;
+    let event = events.next().await.unwrap()?;
+    assert!(matches!(event, NetworkEvent::LinkAdded { ifname, .. } if ifname == "br0"));
+    addr_add(Some(netns_name), "br0", "10.0.0.1/24".parse()?).await?;
+    loop {
+        if let NetworkEvent::AddrAdded { ifname, addr } = events.next().await.unwrap()? {
+            assert_eq!(ifname, "br0");
+            assert_eq!(addr, "10.0.0.1/24".parse::<ipnet::IpNet>()?);
+            break;
+        }
+    }
+    interface_del(Some(netns_name), "br0").await?;
+    loop {
+        if let NetworkEvent::LinkRemoved { ifname, .. } = events.next().await.unwrap()? {
+            assert_eq!(ifname, "br0");
+            break;
+        }
+    }
+
+    drop(events);
+    netns_del(netns_name).await?;
+    Ok(())
+}
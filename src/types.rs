@@ -1,53 +1,165 @@
 use anyhow::{anyhow, Context};
 use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use wireguard_keys::{Privkey, Pubkey, Secret};
 
+/// Address family of addresses and firewall rules.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// Address family of an address.
+    pub fn of(addr: &IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => IpFamily::V4,
+            IpAddr::V6(_) => IpFamily::V6,
+        }
+    }
+}
+
+/// Transport protocol of ports in firewall rules.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            other => Err(anyhow!("Unknown protocol {other:?}")),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NetnsItem {
     pub name: String,
     pub id: Option<usize>,
+    /// Path the namespace is mounted at.
+    #[serde(default)]
+    pub path: PathBuf,
+    /// Inode of the namespace, as shown by `readlink /proc/<pid>/ns/net`.
+    #[serde(default)]
+    pub inode: Option<u64>,
+    /// Whether the namespace still exists. If not, only a dangling mount point is left,
+    /// which can be removed with `netns_del`.
+    #[serde(default)]
+    pub alive: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NetworkStats {
-    pub private_key: Privkey,
-    pub public_key: Pubkey,
+    pub private_key: Option<Privkey>,
+    pub public_key: Option<Pubkey>,
     pub listen_port: u16,
-    pub fwmark: Option<u16>,
+    pub fwmark: Option<u32>,
     pub peers: Vec<PeerStats>,
 }
 
+/// Parses a field of `wg show dump` output which is `(none)` when unset.
+fn parse_optional<T: FromStr>(value: &str) -> Result<Option<T>, anyhow::Error>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match value {
+        "(none)" => Ok(None),
+        value => Ok(Some(value.parse()?)),
+    }
+}
+
+/// Parses a firewall mark, which `wg` prints in hexadecimal or as `off`.
+fn parse_fwmark(value: &str) -> Result<Option<u32>, anyhow::Error> {
+    if value == "off" {
+        return Ok(None);
+    }
+    let fwmark = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => value.parse()?,
+    };
+    Ok(Some(fwmark))
+}
+
 impl FromStr for NetworkStats {
     type Err = anyhow::Error;
     fn from_str(output: &str) -> Result<Self, Self::Err> {
-        let mut lines = output.lines();
+        let mut lines = output.lines().filter(|line| !line.is_empty());
         let network_stats = lines.next().ok_or(anyhow!("Missing network line"))?;
         let components: Vec<&str> = network_stats.split('\t').collect();
+        let mut stats = NetworkStats::from_components(&components)?;
+        stats.peers = lines
+            .map(PeerStats::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(stats)
+    }
+}
+
+impl NetworkStats {
+    fn from_components(components: &[&str]) -> Result<Self, anyhow::Error> {
         if components.len() != 4 {
-            println!("{:?}", components);
-            return Err(anyhow!("Wrong network stats line len"));
+            return Err(anyhow!(
+                "Wrong network stats line len {}, expected 4",
+                components.len()
+            ));
         }
         Ok(NetworkStats {
-            private_key: Privkey::from_str(components[0])?,
-            public_key: Pubkey::from_str(components[1])?,
-            listen_port: components[2].parse()?,
-            fwmark: if components[3] == "off" {
-                None
-            } else {
-                Some(components[3].parse()?)
-            },
-            peers: lines
-                .map(|line| PeerStats::from_str(line))
-                .collect::<Result<Vec<_>, _>>()?,
+            private_key: parse_optional(components[0]).context("Parsing private key")?,
+            public_key: parse_optional(components[1]).context("Parsing public key")?,
+            listen_port: components[2].parse().context("Parsing listen port")?,
+            fwmark: parse_fwmark(components[3]).context("Parsing fwmark")?,
+            peers: vec![],
         })
     }
-}
 
-impl NetworkStats {
+    /// Parses the output of `wg show all dump`, where every line is prefixed with the
+    /// name of the interface it belongs to.
+    pub fn parse_all(output: &str) -> Result<BTreeMap<String, NetworkStats>, anyhow::Error> {
+        let mut interfaces: BTreeMap<String, NetworkStats> = BTreeMap::new();
+        for line in output.lines().filter(|line| !line.is_empty()) {
+            let components: Vec<&str> = line.split('\t').collect();
+            let interface = components[0];
+            match components.len() {
+                5 => {
+                    let stats = NetworkStats::from_components(&components[1..])?;
+                    if interfaces.insert(interface.to_string(), stats).is_some() {
+                        return Err(anyhow!("Duplicate interface {interface} in dump"));
+                    }
+                }
+                9 => {
+                    let peer = PeerStats::from_components(&components[1..])?;
+                    interfaces
+                        .get_mut(interface)
+                        .ok_or(anyhow!("Peer for unknown interface {interface} in dump"))?
+                        .peers
+                        .push(peer);
+                }
+                len => return Err(anyhow!("Wrong dump line len {len}, expected 5 or 9")),
+            }
+        }
+        Ok(interfaces)
+    }
+
     pub fn peers(&self) -> &[PeerStats] {
         &self.peers
     }
@@ -73,21 +185,22 @@ impl FromStr for PeerStats {
     type Err = anyhow::Error;
     fn from_str(output: &str) -> Result<Self, Self::Err> {
         let components: Vec<&str> = output.split('\t').collect();
+        PeerStats::from_components(&components)
+    }
+}
+
+impl PeerStats {
+    fn from_components(components: &[&str]) -> Result<Self, anyhow::Error> {
         if components.len() != 8 {
-            return Err(anyhow!("Wrong network stats line len"));
+            return Err(anyhow!(
+                "Wrong peer stats line len {}, expected 8",
+                components.len()
+            ));
         }
         Ok(PeerStats {
-            public_key: Pubkey::from_str(components[0])?,
-            preshared_key: if components[1] == "(none)" {
-                None
-            } else {
-                Some(Secret::from_str(components[1])?)
-            },
-            endpoint: if components[2] == "(none)" {
-                None
-            } else {
-                Some(components[2].parse().context("Parsing endpoint")?)
-            },
+            public_key: Pubkey::from_str(components[0]).context("Parsing public key")?,
+            preshared_key: parse_optional(components[1]).context("Parsing preshared key")?,
+            endpoint: parse_optional(components[2]).context("Parsing endpoint")?,
             allowed_ips: if components[3] == "(none)" {
                 vec![]
             } else {
@@ -98,7 +211,7 @@ impl FromStr for PeerStats {
                     .context("Parsing IpNet")?
             },
             latest_handshake: {
-                let timestamp: u64 = components[4].parse()?;
+                let timestamp: u64 = components[4].parse().context("Parsing latest handshake")?;
                 if timestamp > 0 {
                     Some(
                         UNIX_EPOCH
@@ -109,19 +222,129 @@ impl FromStr for PeerStats {
                     None
                 }
             },
-            transfer_rx: components[5].parse()?,
-            transfer_tx: components[6].parse()?,
+            transfer_rx: components[5].parse().context("Parsing transfer rx")?,
+            transfer_tx: components[6].parse().context("Parsing transfer tx")?,
             persistent_keepalive: if components[7] == "off" {
                 None
             } else {
-                Some(components[4].parse()?)
+                Some(
+                    components[7]
+                        .parse()
+                        .context("Parsing persistent keepalive")?,
+                )
             },
         })
     }
-}
 
-impl PeerStats {
     pub fn transfer(&self) -> (usize, usize) {
         (self.transfer_rx, self.transfer_tx)
     }
 }
+
+#[cfg(test)]
+const TEST_DUMP: &str = "cFnKhp0uB+UMUgERCZEpMdjDmD5c+Ltw4YyoZchIf1w=\tTxsQfcl4+RSI0RXMvIa1h7/HoeKGiw2EPSa9xjfy8ww=\t51820\t0xca6c
+AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A=\t(none)\t[2001:db8::1]:51820\t10.0.0.2/32,fd00::2/128\t1660000000\t100\t200\t25
+EIlS8mtQ8qf8ofIsMsOtpwpqtyFaRgDE5Kvm/DLRdC0=\tAUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A=\t(none)\t(none)\t0\t0\t0\toff
+";
+
+#[test]
+fn test_network_stats_parse() {
+    let stats = NetworkStats::from_str(TEST_DUMP).unwrap();
+    assert_eq!(
+        stats.public_key,
+        Some(Pubkey::from_str("TxsQfcl4+RSI0RXMvIa1h7/HoeKGiw2EPSa9xjfy8ww=").unwrap())
+    );
+    assert_eq!(stats.listen_port(), 51820);
+    assert_eq!(stats.fwmark, Some(0xca6c));
+    assert_eq!(stats.peers().len(), 2);
+
+    let peer = &stats.peers()[0];
+    assert_eq!(peer.preshared_key, None);
+    assert_eq!(peer.endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+    assert_eq!(
+        peer.allowed_ips,
+        vec![
+            "10.0.0.2/32".parse().unwrap(),
+            "fd00::2/128".parse().unwrap()
+        ]
+    );
+    assert_eq!(
+        peer.latest_handshake,
+        Some(UNIX_EPOCH + Duration::from_secs(1660000000))
+    );
+    assert_eq!(peer.transfer(), (100, 200));
+    assert_eq!(peer.persistent_keepalive, Some(25));
+
+    let peer = &stats.peers()[1];
+    assert!(peer.preshared_key.is_some());
+    assert_eq!(peer.endpoint, None);
+    assert!(peer.allowed_ips.is_empty());
+    assert_eq!(peer.latest_handshake, None);
+    assert_eq!(peer.persistent_keepalive, None);
+}
+
+#[test]
+fn test_network_stats_parse_unconfigured() {
+    let stats = NetworkStats::from_str("(none)\t(none)\t0\toff\n").unwrap();
+    assert_eq!(stats.private_key, None);
+    assert_eq!(stats.public_key, None);
+    assert_eq!(stats.fwmark, None);
+    assert!(stats.peers().is_empty());
+}
+
+#[test]
+fn test_network_stats_parse_all() {
+    let prefix = |name: &str| -> String {
+        TEST_DUMP
+            .lines()
+            .map(|line| format!("{name}\t{line}\n"))
+            .collect()
+    };
+    let dump = format!("{}{}", prefix("wg0"), prefix("wg1"));
+    let interfaces = NetworkStats::parse_all(&dump).unwrap();
+    assert_eq!(interfaces.len(), 2);
+    assert_eq!(interfaces["wg0"].peers().len(), 2);
+    assert_eq!(interfaces["wg1"].fwmark, Some(0xca6c));
+    assert!(NetworkStats::parse_all("").unwrap().is_empty());
+
+    // peers need to follow their interface
+    let dump: String = prefix("wg0").lines().skip(1).collect();
+    assert!(NetworkStats::parse_all(&dump).is_err());
+    // interfaces may only appear once
+    let dump: String = format!("{}{}", prefix("wg0"), prefix("wg0"));
+    assert!(NetworkStats::parse_all(&dump).is_err());
+}
+
+#[test]
+fn test_network_stats_parse_fuzz() {
+    // mutate valid dumps with a fixed xorshift sequence, parsing must never panic
+    let alphabet: Vec<char> = "\t\n0123456789abcdefx:[]/,.()+=-none off".chars().collect();
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize
+    };
+    let all: String = TEST_DUMP
+        .lines()
+        .map(|line| format!("wg0\t{line}\n"))
+        .collect();
+    for _ in 0..20000 {
+        let source = if next() % 2 == 0 { TEST_DUMP } else { &all };
+        let mut input: Vec<char> = source.chars().collect();
+        for _ in 0..(next() % 4 + 1) {
+            let position = next() % input.len();
+            match next() % 3 {
+                0 => input[position] = alphabet[next() % alphabet.len()],
+                1 => {
+                    input.remove(position);
+                }
+                _ => input.insert(position, alphabet[next() % alphabet.len()]),
+            }
+        }
+        let input: String = input.into_iter().collect();
+        let _ = NetworkStats::from_str(&input);
+        let _ = NetworkStats::parse_all(&input);
+    }
+}
@@ -0,0 +1,713 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Routing table that routes for a WireGuard interface's allowed IPs are added to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub enum WireguardTable {
+    /// Do not add any routes.
+    Off,
+    /// Add routes to the main routing table.
+    #[default]
+    Auto,
+    /// Add routes to the given routing table, by name or number.
+    Table(String),
+}
+
+impl fmt::Display for WireguardTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireguardTable::Off => write!(f, "off"),
+            WireguardTable::Auto => write!(f, "auto"),
+            WireguardTable::Table(table) => write!(f, "{table}"),
+        }
+    }
+}
+
+impl FromStr for WireguardTable {
+    type Err = anyhow::Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(WireguardTable::Off),
+            "auto" => Ok(WireguardTable::Auto),
+            "" => Err(anyhow!("Empty routing table")),
+            table => Ok(WireguardTable::Table(table.to_string())),
+        }
+    }
+}
+
+/// Endpoint of a WireGuard peer, either an address or a hostname which is resolved when
+/// the configuration is applied.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum WireguardEndpoint {
+    Addr(SocketAddr),
+    Host { host: String, port: u16 },
+}
+
+impl WireguardEndpoint {
+    /// Returns the address of this endpoint, if it is not a hostname.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            WireguardEndpoint::Addr(addr) => Some(*addr),
+            WireguardEndpoint::Host { .. } => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for WireguardEndpoint {
+    fn from(addr: SocketAddr) -> Self {
+        WireguardEndpoint::Addr(addr)
+    }
+}
+
+impl fmt::Display for WireguardEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireguardEndpoint::Addr(addr) => write!(f, "{addr}"),
+            WireguardEndpoint::Host { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+impl FromStr for WireguardEndpoint {
+    type Err = anyhow::Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = value.parse() {
+            return Ok(WireguardEndpoint::Addr(addr));
+        }
+        let (host, port) = value
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Endpoint {value:?} is missing a port"))?;
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == ':') {
+            return Err(anyhow!("Invalid endpoint host {host:?}"));
+        }
+        Ok(WireguardEndpoint::Host {
+            host: host.to_string(),
+            port: port.parse().context("Parsing endpoint port")?,
+        })
+    }
+}
+
+#[test]
+fn test_wireguard_endpoint() {
+    assert_eq!(
+        WireguardEndpoint::from_str("1.2.3.4:51820").unwrap(),
+        WireguardEndpoint::Addr("1.2.3.4:51820".parse().unwrap())
+    );
+    assert_eq!(
+        WireguardEndpoint::from_str("[2001:db8::1]:51820").unwrap(),
+        WireguardEndpoint::Addr("[2001:db8::1]:51820".parse().unwrap())
+    );
+    let endpoint = WireguardEndpoint::from_str("vpn.example.com:51820").unwrap();
+    assert_eq!(
+        endpoint,
+        WireguardEndpoint::Host {
+            host: "vpn.example.com".into(),
+            port: 51820
+        }
+    );
+    assert_eq!(endpoint.to_string(), "vpn.example.com:51820");
+    assert!(WireguardEndpoint::from_str("vpn.example.com").is_err());
+    assert!(WireguardEndpoint::from_str(":51820").is_err());
+    assert!(WireguardEndpoint::from_str("2001:db8::1:51820x").is_err());
+}
+
+/// `[Interface]` section of a wg-quick configuration. Besides the fields understood
+/// by `wg`, this contains the fields that wg-quick uses to set up the interface.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct WireguardInterfaceConfig {
+    pub private_key: Option<Privkey>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub address: Vec<IpNet>,
+    pub dns: Vec<IpAddr>,
+    pub dns_search: Vec<String>,
+    pub mtu: Option<usize>,
+    pub table: WireguardTable,
+    pub pre_up: Vec<String>,
+    pub post_up: Vec<String>,
+    pub pre_down: Vec<String>,
+    pub post_down: Vec<String>,
+    pub save_config: bool,
+}
+
+/// `[Peer]` section of a WireGuard configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WireguardPeerConfig {
+    pub public_key: Pubkey,
+    pub preshared_key: Option<Secret>,
+    pub allowed_ips: Vec<IpNet>,
+    pub endpoint: Option<WireguardEndpoint>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl WireguardPeerConfig {
+    pub fn new(public_key: Pubkey) -> Self {
+        WireguardPeerConfig {
+            public_key,
+            preshared_key: None,
+            allowed_ips: vec![],
+            endpoint: None,
+            persistent_keepalive: None,
+        }
+    }
+}
+
+/// WireGuard configuration in the format used by wg-quick.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct WireguardConfig {
+    pub interface: WireguardInterfaceConfig,
+    pub peers: Vec<WireguardPeerConfig>,
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+}
+
+/// Parse an address with optional prefix length, addresses without one are single hosts.
+fn parse_net(value: &str) -> Result<IpNet> {
+    match value.parse() {
+        Ok(net) => Ok(net),
+        Err(error) => match value.parse::<IpAddr>() {
+            Ok(addr) => Ok(IpNet::from(addr)),
+            Err(_) => Err(error.into()),
+        },
+    }
+}
+
+fn join_list<T: ToString>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl WireguardInterfaceConfig {
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "privatekey" => self.private_key = Some(Privkey::from_str(value)?),
+            "listenport" => self.listen_port = Some(value.parse()?),
+            "fwmark" => {
+                self.fwmark = match value {
+                    "off" => None,
+                    value => Some(match value.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16)?,
+                        None => value.parse()?,
+                    }),
+                }
+            }
+            "address" => {
+                for address in split_list(value) {
+                    self.address.push(parse_net(address)?);
+                }
+            }
+            "dns" => {
+                for entry in split_list(value) {
+                    match entry.parse() {
+                        Ok(addr) => self.dns.push(addr),
+                        Err(_) => self.dns_search.push(entry.to_string()),
+                    }
+                }
+            }
+            "mtu" => self.mtu = Some(value.parse()?),
+            "table" => self.table = value.parse()?,
+            "preup" => self.pre_up.push(value.to_string()),
+            "postup" => self.post_up.push(value.to_string()),
+            "predown" => self.pre_down.push(value.to_string()),
+            "postdown" => self.post_down.push(value.to_string()),
+            "saveconfig" => self.save_config = value == "true",
+            _ => return Err(anyhow!("Unknown interface field")),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct PeerBuilder {
+    public_key: Option<Pubkey>,
+    preshared_key: Option<Secret>,
+    allowed_ips: Vec<IpNet>,
+    endpoint: Option<WireguardEndpoint>,
+    persistent_keepalive: Option<u16>,
+}
+
+impl PeerBuilder {
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "publickey" => self.public_key = Some(Pubkey::from_str(value)?),
+            "presharedkey" => self.preshared_key = Some(Secret::from_str(value)?),
+            "allowedips" => {
+                for ip in split_list(value) {
+                    self.allowed_ips.push(parse_net(ip)?);
+                }
+            }
+            "endpoint" => self.endpoint = Some(value.parse()?),
+            "persistentkeepalive" => {
+                self.persistent_keepalive = match value {
+                    "off" => None,
+                    value => Some(value.parse()?),
+                }
+            }
+            _ => return Err(anyhow!("Unknown peer field")),
+        }
+        Ok(())
+    }
+
+    fn build(self) -> Result<WireguardPeerConfig> {
+        Ok(WireguardPeerConfig {
+            public_key: self
+                .public_key
+                .ok_or_else(|| anyhow!("Peer is missing PublicKey"))?,
+            preshared_key: self.preshared_key,
+            allowed_ips: self.allowed_ips,
+            endpoint: self.endpoint,
+            persistent_keepalive: self.persistent_keepalive,
+        })
+    }
+}
+
+impl FromStr for WireguardConfig {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        enum Section {
+            None,
+            Interface,
+            Peer(PeerBuilder),
+        }
+        let mut config = WireguardConfig::default();
+        let mut section = Section::None;
+        let mut seen_interface = false;
+        for (number, line) in input.lines().enumerate() {
+            let number = number + 1;
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Section::Peer(peer) = std::mem::replace(&mut section, Section::None) {
+                    config.peers.push(peer.build()?);
+                }
+                section = match line[1..line.len() - 1].trim().to_lowercase().as_str() {
+                    "interface" if !seen_interface => {
+                        seen_interface = true;
+                        Section::Interface
+                    }
+                    "interface" => return Err(anyhow!("Duplicate [Interface] on line {number}")),
+                    "peer" => Section::Peer(PeerBuilder::default()),
+                    _ => return Err(anyhow!("Unknown section {line} on line {number}")),
+                };
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected key = value on line {number}"))?;
+            let key = key.trim().to_lowercase();
+            let value = value.trim();
+            match &mut section {
+                Section::None => return Err(anyhow!("Field outside of section on line {number}")),
+                Section::Interface => config.interface.set(&key, value),
+                Section::Peer(peer) => peer.set(&key, value),
+            }
+            .with_context(|| format!("Parsing {key} on line {number}"))?;
+        }
+        if let Section::Peer(peer) = section {
+            config.peers.push(peer.build()?);
+        }
+        Ok(config)
+    }
+}
+
+impl WireguardConfig {
+    /// Render only the fields understood by `wg setconf` and `wg syncconf`.
+    pub fn to_wg_config(&self) -> String {
+        let mut output = String::new();
+        self.write(&mut output, false).unwrap();
+        output
+    }
+
+    fn write(&self, f: &mut impl fmt::Write, quick: bool) -> fmt::Result {
+        let interface = &self.interface;
+        writeln!(f, "[Interface]")?;
+        if let Some(private_key) = &interface.private_key {
+            writeln!(f, "PrivateKey = {private_key}")?;
+        }
+        if let Some(listen_port) = interface.listen_port {
+            writeln!(f, "ListenPort = {listen_port}")?;
+        }
+        if let Some(fwmark) = interface.fwmark {
+            writeln!(f, "FwMark = {fwmark:#x}")?;
+        }
+        if quick {
+            if !interface.address.is_empty() {
+                writeln!(f, "Address = {}", join_list(&interface.address))?;
+            }
+            if !interface.dns.is_empty() || !interface.dns_search.is_empty() {
+                let mut dns: Vec<String> =
+                    interface.dns.iter().map(|dns| dns.to_string()).collect();
+                dns.extend(interface.dns_search.iter().cloned());
+                writeln!(f, "DNS = {}", join_list(&dns))?;
+            }
+            if let Some(mtu) = interface.mtu {
+                writeln!(f, "MTU = {mtu}")?;
+            }
+            if interface.table != WireguardTable::Auto {
+                writeln!(f, "Table = {}", interface.table)?;
+            }
+            for (key, hooks) in [
+                ("PreUp", &interface.pre_up),
+                ("PostUp", &interface.post_up),
+                ("PreDown", &interface.pre_down),
+                ("PostDown", &interface.post_down),
+            ] {
+                for hook in hooks {
+                    writeln!(f, "{key} = {hook}")?;
+                }
+            }
+            if interface.save_config {
+                writeln!(f, "SaveConfig = true")?;
+            }
+        }
+        for peer in &self.peers {
+            writeln!(f)?;
+            writeln!(f, "[Peer]")?;
+            writeln!(f, "PublicKey = {}", peer.public_key)?;
+            if let Some(preshared_key) = &peer.preshared_key {
+                writeln!(f, "PresharedKey = {preshared_key}")?;
+            }
+            if !peer.allowed_ips.is_empty() {
+                writeln!(f, "AllowedIPs = {}", join_list(&peer.allowed_ips))?;
+            }
+            if let Some(endpoint) = &peer.endpoint {
+                writeln!(f, "Endpoint = {endpoint}")?;
+            }
+            if let Some(keepalive) = peer.persistent_keepalive {
+                writeln!(f, "PersistentKeepalive = {keepalive}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for WireguardConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, true)
+    }
+}
+
+/// Read and parse a wg-quick configuration file.
+pub async fn wireguard_config_read(path: &Path) -> Result<WireguardConfig> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Reading wireguard config {path:?}"))?;
+    WireguardConfig::from_str(&data).with_context(|| format!("Parsing wireguard config {path:?}"))
+}
+
+/// Run wg-quick hooks inside a network namespace, with `%i` replaced by the interface name.
+async fn wireguard_hooks(netns: &str, name: &str, hooks: &[String]) -> Result<()> {
+    for hook in hooks {
+        let hook = hook.replace("%i", name);
+        info!("wireguard hook {netns}, {name}: {hook}");
+        if !Command::new(IP_PATH)
+            .arg("netns")
+            .arg("exec")
+            .arg(netns)
+            .arg("sh")
+            .arg("-c")
+            .arg(&hook)
+            .status()
+            .await?
+            .success()
+        {
+            return Err(anyhow!("Error running hook {hook:?} for {name} in {netns}"));
+        }
+    }
+    Ok(())
+}
+
+fn wireguard_resolv_conf(interface: &WireguardInterfaceConfig) -> String {
+    let mut output = String::new();
+    for dns in &interface.dns {
+        output.push_str(&format!("nameserver {dns}\n"));
+    }
+    if !interface.dns_search.is_empty() {
+        output.push_str(&format!("search {}\n", interface.dns_search.join(" ")));
+    }
+    output
+}
+
+/// Path of a file in the configuration folder of a network namespace.
+fn netns_config_path(netns: &str, filename: &str) -> PathBuf {
+    let mut path = PathBuf::from("/etc/netns");
+    path.push(netns);
+    path.push(filename);
+    path
+}
+
+/// Path the `resolv.conf` of a namespace is moved to while an interface overrides it.
+fn wireguard_resolv_conf_backup(netns: &str, name: &str) -> PathBuf {
+    netns_config_path(netns, &format!("resolv.conf.{name}.orig"))
+}
+
+/// Routes for an allowed IP. In the main table, a default route is split into its two
+/// halves, which take precedence over the existing default route without replacing it,
+/// so it is still in place once the interface is removed.
+fn wireguard_routes(allowed_ip: &IpNet, main: bool) -> Vec<IpNet> {
+    let allowed_ip = allowed_ip.trunc();
+    match allowed_ip.prefix_len() {
+        0 if main => allowed_ip.subnets(1).unwrap().collect(),
+        _ => vec![allowed_ip],
+    }
+}
+
+async fn wireguard_configure(netns: &str, name: &str, config: &WireguardConfig) -> Result<()> {
+    let interface = &config.interface;
+    let filename = PathBuf::from(format!("wireguard/{name}.conf"));
+    netns_write_file_secret(netns, &filename, &config.to_wg_config()).await?;
+    wireguard_syncconf(netns, name).await?;
+    for address in &interface.address {
+        addr_add(Some(netns), name, *address).await?;
+    }
+    if let Some(mtu) = interface.mtu {
+        interface_mtu(Some(netns), name, mtu).await?;
+    }
+    interface_up(Some(netns), name).await?;
+    let table = match &interface.table {
+        WireguardTable::Off => None,
+        WireguardTable::Auto => Some(None),
+        WireguardTable::Table(table) => Some(Some(table.as_str())),
+    };
+    if let Some(table) = table {
+        for peer in &config.peers {
+            for allowed_ip in &peer.allowed_ips {
+                for route in wireguard_routes(allowed_ip, table.is_none()) {
+                    route_replace(Some(netns), route, name, table).await?;
+                }
+            }
+        }
+    }
+    if !interface.dns.is_empty() || !interface.dns_search.is_empty() {
+        let backup = wireguard_resolv_conf_backup(netns, name);
+        match tokio::fs::rename(netns_config_path(netns, "resolv.conf"), &backup).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(error).context("Backing up resolv.conf");
+            }
+            _ => {}
+        }
+        netns_write_file(
+            netns,
+            Path::new("resolv.conf"),
+            &wireguard_resolv_conf(interface),
+        )
+        .await?;
+    }
+    wireguard_hooks(netns, name, &interface.post_up).await?;
+    Ok(())
+}
+
+/// Bring up a WireGuard interface in a network namespace from a wg-quick configuration,
+/// like `wg-quick up` does. Runs the PreUp hooks, creates the interface, applies the
+/// WireGuard configuration, adds the addresses, sets the MTU, brings the interface up,
+/// adds routes for the allowed IPs of every peer, writes the DNS servers into the
+/// namespace's `resolv.conf` and runs the PostUp hooks. An existing `resolv.conf` is
+/// kept aside and restored by [`wireguard_down`].
+///
+/// With `Table = auto`, an allowed IP of `0.0.0.0/0` or `::/0` is routed as its two
+/// halves, so the existing default route of the namespace is kept and is back in use
+/// once the interface is removed.
+///
+/// If any step after creating the interface fails, the interface is removed again.
+///
+/// The configuration and `resolv.conf` are kept in `/etc/netns/<netns>`, which only
+/// applies to named namespaces. Use [`netns_attach`] to name the namespace of a process.
+pub async fn wireguard_up(netns: &str, name: &str, config: &WireguardConfig) -> Result<()> {
+    info!("wireguard_up({netns}, {name})");
+    wireguard_hooks(netns, name, &config.interface.pre_up).await?;
+    wireguard_create(Some(netns), name).await?;
+    if let Err(error) = wireguard_configure(netns, name, config).await {
+        warn!("Error bringing up wireguard interface {name} in {netns}, rolling back: {error}");
+        if let Err(error) = wireguard_teardown(netns, name, config).await {
+            warn!("Error rolling back wireguard interface {name} in {netns}: {error}");
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+async fn wireguard_teardown(netns: &str, name: &str, config: &WireguardConfig) -> Result<()> {
+    let interface = &config.interface;
+    if !interface.dns.is_empty() || !interface.dns_search.is_empty() {
+        wireguard_resolv_conf_restore(netns, name, interface).await?;
+    }
+    let path = netns_config_path(netns, &format!("wireguard/{name}.conf"));
+    match tokio::fs::remove_file(&path).await {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+            return Err(error).context("Removing wireguard config");
+        }
+        _ => {}
+    }
+    interface_del(Some(netns), name).await
+}
+
+/// Put back the `resolv.conf` that was in place before the interface came up. The file
+/// is only touched if it still is the one written for the interface, so changes made
+/// in the meantime are kept.
+async fn wireguard_resolv_conf_restore(
+    netns: &str,
+    name: &str,
+    interface: &WireguardInterfaceConfig,
+) -> Result<()> {
+    let path = netns_config_path(netns, "resolv.conf");
+    let backup = wireguard_resolv_conf_backup(netns, name);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(data) if data == wireguard_resolv_conf(interface) => {}
+        Ok(_) => {
+            warn!("Keeping modified resolv.conf of {netns}, original is at {backup:?}");
+            return Ok(());
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error).context("Reading resolv.conf"),
+    }
+    match tokio::fs::rename(&backup, &path).await {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            tokio::fs::remove_file(&path)
+                .await
+                .context("Removing resolv.conf")?;
+        }
+        result => result.context("Restoring resolv.conf")?,
+    }
+    Ok(())
+}
+
+/// Take down a WireGuard interface brought up by [`wireguard_up`], like `wg-quick down`
+/// does. Runs the PreDown hooks, removes the interface along with its routes, DNS
+/// configuration and rendered configuration file and runs the PostDown hooks.
+///
+/// `SaveConfig` is not supported, the running configuration is not written back. Like
+/// [`wireguard_up`], this only works with named namespaces.
+pub async fn wireguard_down(netns: &str, name: &str, config: &WireguardConfig) -> Result<()> {
+    info!("wireguard_down({netns}, {name})");
+    if config.interface.save_config {
+        warn!("SaveConfig is not supported, configuration of {name} in {netns} is not saved");
+    }
+    wireguard_hooks(netns, name, &config.interface.pre_down).await?;
+    wireguard_teardown(netns, name, config).await?;
+    wireguard_hooks(netns, name, &config.interface.post_down).await?;
+    Ok(())
+}
+
+#[test]
+fn test_wireguard_routes() {
+    let net = |net: &str| net.parse::<IpNet>().unwrap();
+    assert_eq!(
+        wireguard_routes(&net("10.0.0.1/24"), true),
+        vec![net("10.0.0.0/24")]
+    );
+    assert_eq!(
+        wireguard_routes(&net("0.0.0.0/0"), true),
+        vec![net("0.0.0.0/1"), net("128.0.0.0/1")]
+    );
+    assert_eq!(
+        wireguard_routes(&net("::/0"), true),
+        vec![net("::/1"), net("8000::/1")]
+    );
+    assert_eq!(
+        wireguard_routes(&net("0.0.0.0/0"), false),
+        vec![net("0.0.0.0/0")]
+    );
+}
+
+#[test]
+fn test_wireguard_config_parse() {
+    let config = r#"
+# managed by fractal
+[Interface]
+PrivateKey = cFnKhp0uB+UMUgERCZEpMdjDmD5c+Ltw4YyoZchIf1w=
+ListenPort = 51820
+Address = 10.0.0.1/24, fd00::1/64
+DNS = 10.0.0.53, example.com
+MTU = 1380
+Table = 1234
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+PostUp = echo up
+
+[Peer]
+PublicKey = AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A=
+AllowedIPs = 10.0.0.2/32
+Endpoint = 1.2.3.4:51820 # office
+PersistentKeepalive = 25
+
+[peer]
+publickey = EIlS8mtQ8qf8ofIsMsOtpwpqtyFaRgDE5Kvm/DLRdC0=
+PresharedKey = TxsQfcl4+RSI0RXMvIa1h7/HoeKGiw2EPSa9xjfy8ww=
+AllowedIPs = 0.0.0.0/0,::/0
+"#;
+    let config = WireguardConfig::from_str(config).unwrap();
+    let interface = &config.interface;
+    assert!(interface.private_key.is_some());
+    assert_eq!(interface.listen_port, Some(51820));
+    assert_eq!(
+        interface.address,
+        vec![
+            "10.0.0.1/24".parse().unwrap(),
+            "fd00::1/64".parse().unwrap()
+        ]
+    );
+    assert_eq!(interface.dns, vec!["10.0.0.53".parse::<IpAddr>().unwrap()]);
+    assert_eq!(interface.dns_search, vec!["example.com".to_string()]);
+    assert_eq!(interface.mtu, Some(1380));
+    assert_eq!(interface.table, WireguardTable::Table("1234".into()));
+    assert_eq!(interface.post_up.len(), 2);
+    assert_eq!(config.peers.len(), 2);
+    assert_eq!(
+        config.peers[0].endpoint,
+        Some(WireguardEndpoint::Addr("1.2.3.4:51820".parse().unwrap()))
+    );
+    assert_eq!(config.peers[0].persistent_keepalive, Some(25));
+    assert!(config.peers[1].preshared_key.is_some());
+    assert_eq!(config.peers[1].allowed_ips.len(), 2);
+
+    // rendering and parsing again yields the same config
+    let rendered = config.to_string();
+    assert_eq!(WireguardConfig::from_str(&rendered).unwrap(), config);
+
+    // wg config omits the wg-quick fields
+    let wg = config.to_wg_config();
+    assert!(wg.contains("ListenPort = 51820"));
+    assert!(!wg.contains("Address"));
+    assert!(!wg.contains("PostUp"));
+    assert_eq!(
+        wireguard_resolv_conf(interface),
+        "nameserver 10.0.0.53\nsearch example.com\n"
+    );
+}
+
+#[test]
+fn test_wireguard_config_parse_errors() {
+    assert!(WireguardConfig::from_str("PrivateKey = abc").is_err());
+    assert!(WireguardConfig::from_str("[Interface]\nFoo = bar").is_err());
+    assert!(WireguardConfig::from_str("[Interface]\n[Interface]").is_err());
+    assert!(WireguardConfig::from_str("[Peer]\nAllowedIPs = 10.0.0.0/8").is_err());
+    assert!(WireguardConfig::from_str("[Interface]\nAddress = 10.0.0.1/33").is_err());
+    assert!(WireguardConfig::from_str("[Interface]\nAddress = 10.0.0").is_err());
+}
+
+#[test]
+fn test_wireguard_config_parse_host_address() {
+    let config = WireguardConfig::from_str("[Interface]\nAddress = 10.0.0.1, fd00::1\n").unwrap();
+    assert_eq!(
+        config.interface.address,
+        vec![
+            "10.0.0.1/32".parse().unwrap(),
+            "fd00::1/128".parse().unwrap()
+        ]
+    );
+}
@@ -0,0 +1,312 @@
+use crate::{wireguard_stats, NetworkStats, PeerStats};
+use anyhow::Result;
+use log::*;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use wireguard_keys::Pubkey;
+
+/// Age after which a peer's latest handshake is considered stale. WireGuard rekeys
+/// every 120 seconds while traffic flows and gives up after 180 seconds, so a peer
+/// without a handshake in this window is not connected anymore.
+pub const WIREGUARD_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Default interval at which [`WireguardMonitor`] polls the interface statistics.
+pub const WIREGUARD_MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Change observed on a WireGuard interface between two polls.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WireguardEvent {
+    /// Peer was added to the interface.
+    PeerAdded { peer: Pubkey },
+    /// Peer was removed from the interface.
+    PeerRemoved { peer: Pubkey },
+    /// Peer completed a handshake within the handshake timeout.
+    PeerConnected { peer: Pubkey },
+    /// Peer has not completed a handshake within the handshake timeout.
+    PeerDisconnected { peer: Pubkey },
+    /// Peer endpoint changed, for example because the peer moved to another network.
+    EndpointRoamed {
+        peer: Pubkey,
+        from: Option<SocketAddr>,
+        to: Option<SocketAddr>,
+    },
+    /// Throughput of a peer since the previous poll, in bytes per second. Emitted when
+    /// the peer transferred data, and once with zero rates when it stops doing so.
+    Throughput {
+        peer: Pubkey,
+        rx_rate: f64,
+        tx_rate: f64,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct PeerState {
+    endpoint: Option<SocketAddr>,
+    connected: bool,
+    transfer_rx: usize,
+    transfer_tx: usize,
+    /// Peer transferred data since the poll before.
+    active: bool,
+}
+
+/// Polls the statistics of a WireGuard interface and turns the differences between
+/// snapshots into [`WireguardEvent`]s.
+///
+/// The first snapshot reports every peer as added (and connected, if it is), so that
+/// consumers can build their initial state from the events alone.
+#[derive(Clone, Debug)]
+pub struct WireguardMonitor {
+    netns: String,
+    interface: String,
+    interval: Duration,
+    handshake_timeout: Duration,
+    peers: BTreeMap<Pubkey, PeerState>,
+    last_poll: Option<Instant>,
+}
+
+impl WireguardMonitor {
+    pub fn new(netns: &str, interface: &str) -> Self {
+        WireguardMonitor {
+            netns: netns.to_string(),
+            interface: interface.to_string(),
+            interval: WIREGUARD_MONITOR_INTERVAL,
+            handshake_timeout: WIREGUARD_HANDSHAKE_TIMEOUT,
+            peers: BTreeMap::new(),
+            last_poll: None,
+        }
+    }
+
+    /// Set the interval at which statistics are polled.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the handshake age after which a peer is considered disconnected.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    fn is_connected(&self, peer: &PeerStats, now: SystemTime) -> bool {
+        match peer.latest_handshake {
+            Some(handshake) => match now.duration_since(handshake) {
+                Ok(age) => age <= self.handshake_timeout,
+                // handshake in the future means clocks disagree, but it is recent
+                Err(_) => true,
+            },
+            None => false,
+        }
+    }
+
+    /// Feed a snapshot into the monitor and return the events it produces. The
+    /// `elapsed` duration is the time since the previous snapshot and is used to
+    /// compute throughput rates.
+    pub fn update(
+        &mut self,
+        stats: &NetworkStats,
+        now: SystemTime,
+        elapsed: Option<Duration>,
+    ) -> Vec<WireguardEvent> {
+        let mut events = vec![];
+        let mut peers = BTreeMap::new();
+        for peer in stats.peers() {
+            let mut state = PeerState {
+                endpoint: peer.endpoint,
+                connected: self.is_connected(peer, now),
+                transfer_rx: peer.transfer_rx,
+                transfer_tx: peer.transfer_tx,
+                active: false,
+            };
+            let key = peer.public_key;
+            match self.peers.get(&key) {
+                None => {
+                    events.push(WireguardEvent::PeerAdded { peer: key });
+                    if state.connected {
+                        events.push(WireguardEvent::PeerConnected { peer: key });
+                    }
+                }
+                Some(previous) => {
+                    if previous.endpoint != state.endpoint {
+                        events.push(WireguardEvent::EndpointRoamed {
+                            peer: key,
+                            from: previous.endpoint,
+                            to: state.endpoint,
+                        });
+                    }
+                    match (previous.connected, state.connected) {
+                        (false, true) => events.push(WireguardEvent::PeerConnected { peer: key }),
+                        (true, false) => {
+                            events.push(WireguardEvent::PeerDisconnected { peer: key })
+                        }
+                        _ => {}
+                    }
+                    state.active = previous.active;
+                    if let Some(elapsed) = elapsed.filter(|elapsed| !elapsed.is_zero()) {
+                        // counters reset when a peer is removed and added again
+                        let rx = state.transfer_rx.saturating_sub(previous.transfer_rx);
+                        let tx = state.transfer_tx.saturating_sub(previous.transfer_tx);
+                        state.active = rx > 0 || tx > 0;
+                        if state.active || previous.active {
+                            events.push(WireguardEvent::Throughput {
+                                peer: key,
+                                rx_rate: rx as f64 / elapsed.as_secs_f64(),
+                                tx_rate: tx as f64 / elapsed.as_secs_f64(),
+                            });
+                        }
+                    }
+                }
+            }
+            peers.insert(key, state);
+        }
+        for (key, previous) in &self.peers {
+            if !peers.contains_key(key) {
+                if previous.connected {
+                    events.push(WireguardEvent::PeerDisconnected { peer: *key });
+                }
+                events.push(WireguardEvent::PeerRemoved { peer: *key });
+            }
+        }
+        self.peers = peers;
+        events
+    }
+
+    /// Fetch the current statistics of the interface and return the resulting events.
+    pub async fn poll(&mut self) -> Result<Vec<WireguardEvent>> {
+        let stats = wireguard_stats(self.netns.as_str(), &self.interface).await?;
+        let now = Instant::now();
+        let elapsed = self.last_poll.map(|last| now.duration_since(last));
+        self.last_poll = Some(now);
+        Ok(self.update(&stats, SystemTime::now(), elapsed))
+    }
+
+    /// Poll the interface in the background and return a stream of events. Errors
+    /// fetching the statistics are passed on, polling continues after them. The
+    /// background task stops when the stream is dropped.
+    pub fn stream(mut self) -> impl Stream<Item = Result<WireguardEvent>> {
+        let (sender, receiver) = mpsc::channel(64);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                let events = match self.poll().await {
+                    Ok(events) => events.into_iter().map(Ok).collect(),
+                    Err(error) => {
+                        warn!(
+                            "Error polling wireguard stats for {} in {}: {error}",
+                            self.interface, self.netns
+                        );
+                        vec![Err(error)]
+                    }
+                };
+                for event in events {
+                    if sender.send(event).await.is_err() {
+                        return;
+                    }
+                }
+                if sender.is_closed() {
+                    return;
+                }
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+}
+
+#[cfg(test)]
+fn test_stats(peers: &[(Pubkey, Option<SystemTime>, &str, usize, usize)]) -> NetworkStats {
+    use wireguard_keys::Privkey;
+    let private_key = Privkey::generate();
+    NetworkStats {
+        private_key: Some(private_key),
+        public_key: Some(private_key.pubkey()),
+        listen_port: 51820,
+        fwmark: None,
+        peers: peers
+            .iter()
+            .map(|(key, handshake, endpoint, rx, tx)| PeerStats {
+                public_key: *key,
+                preshared_key: None,
+                endpoint: endpoint.parse().ok(),
+                allowed_ips: vec![],
+                latest_handshake: *handshake,
+                transfer_rx: *rx,
+                transfer_tx: *tx,
+                persistent_keepalive: None,
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_wireguard_monitor_events() {
+    use wireguard_keys::Privkey;
+    let peer = Privkey::generate().pubkey();
+    let other = Privkey::generate().pubkey();
+    let now = SystemTime::now();
+    let recent = Some(now - Duration::from_secs(5));
+    let stale = Some(now - Duration::from_secs(300));
+    let mut monitor = WireguardMonitor::new("netns", "wg0");
+
+    // initial snapshot reports peers as added
+    let stats = test_stats(&[
+        (peer, recent, "1.2.3.4:51820", 0, 0),
+        (other, None, "", 0, 0),
+    ]);
+    assert_eq!(
+        monitor.update(&stats, now, None),
+        vec![
+            WireguardEvent::PeerAdded { peer },
+            WireguardEvent::PeerConnected { peer },
+            WireguardEvent::PeerAdded { peer: other },
+        ]
+    );
+
+    // traffic, roaming and a handshake of the other peer
+    let stats = test_stats(&[
+        (peer, recent, "5.6.7.8:51820", 2000, 1000),
+        (other, recent, "", 0, 0),
+    ]);
+    assert_eq!(
+        monitor.update(&stats, now, Some(Duration::from_secs(2))),
+        vec![
+            WireguardEvent::EndpointRoamed {
+                peer,
+                from: Some("1.2.3.4:51820".parse().unwrap()),
+                to: Some("5.6.7.8:51820".parse().unwrap()),
+            },
+            WireguardEvent::Throughput {
+                peer,
+                rx_rate: 1000.0,
+                tx_rate: 500.0
+            },
+            WireguardEvent::PeerConnected { peer: other },
+        ]
+    );
+
+    // handshake goes stale, traffic stops, other peer is removed
+    let stats = test_stats(&[(peer, stale, "5.6.7.8:51820", 2000, 1000)]);
+    assert_eq!(
+        monitor.update(&stats, now, Some(Duration::from_secs(1))),
+        vec![
+            WireguardEvent::PeerDisconnected { peer },
+            WireguardEvent::Throughput {
+                peer,
+                rx_rate: 0.0,
+                tx_rate: 0.0
+            },
+            WireguardEvent::PeerDisconnected { peer: other },
+            WireguardEvent::PeerRemoved { peer: other },
+        ]
+    );
+
+    // zero throughput is only reported once
+    assert_eq!(
+        monitor.update(&stats, now, Some(Duration::from_secs(1))),
+        vec![]
+    );
+}
@@ -0,0 +1,280 @@
+use crate::{
+    wireguard_peer_endpoint, wireguard_stats, NetworkStats, WireguardEndpoint, WireguardPeerConfig,
+};
+use anyhow::{Context, Result};
+use log::*;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use wireguard_keys::Pubkey;
+
+/// Handshake age after which the endpoint of a peer is resolved again. This matches
+/// the `reresolve-dns.sh` script shipped with wireguard-tools.
+pub const WIREGUARD_RERESOLVE_TIMEOUT: Duration = Duration::from_secs(135);
+
+/// Default interval at which [`WireguardReresolver`] checks the peers.
+pub const WIREGUARD_RERESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Resolves hostnames of peer endpoints to addresses.
+pub trait Resolver {
+    fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<Vec<SocketAddr>>> + Send;
+}
+
+/// Resolver using the system's name resolution.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs = tokio::net::lookup_host((host, port))
+            .await
+            .with_context(|| format!("Resolving {host}"))?;
+        Ok(addrs.collect())
+    }
+}
+
+/// Periodically resolves the hostname endpoints of WireGuard peers again and updates
+/// the interface when the address behind a hostname changed, like wireguard-tools'
+/// `reresolve-dns.sh` does. Only peers whose latest handshake is older than the
+/// timeout are resolved, since a peer with a recent handshake is reachable.
+#[derive(Clone, Debug)]
+pub struct WireguardReresolver<R: Resolver = SystemResolver> {
+    netns: String,
+    interface: String,
+    peers: Vec<WireguardPeerConfig>,
+    resolver: R,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl WireguardReresolver<SystemResolver> {
+    pub fn new(netns: &str, interface: &str, peers: &[WireguardPeerConfig]) -> Self {
+        WireguardReresolver::with_resolver(netns, interface, peers, SystemResolver)
+    }
+}
+
+impl<R: Resolver> WireguardReresolver<R> {
+    pub fn with_resolver(
+        netns: &str,
+        interface: &str,
+        peers: &[WireguardPeerConfig],
+        resolver: R,
+    ) -> Self {
+        WireguardReresolver {
+            netns: netns.to_string(),
+            interface: interface.to_string(),
+            peers: peers.to_vec(),
+            resolver,
+            interval: WIREGUARD_RERESOLVE_INTERVAL,
+            timeout: WIREGUARD_RERESOLVE_TIMEOUT,
+        }
+    }
+
+    /// Set the interval at which peers are checked.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the handshake age after which a peer's endpoint is resolved again.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Determine which peers need their endpoint updated, given the current state of
+    /// the interface. Returns the peers along with their newly resolved address.
+    pub async fn check(
+        &self,
+        stats: &NetworkStats,
+        now: SystemTime,
+    ) -> Result<Vec<(Pubkey, SocketAddr)>> {
+        let mut updates = vec![];
+        for peer in &self.peers {
+            let (host, port) = match &peer.endpoint {
+                Some(WireguardEndpoint::Host { host, port }) => (host, *port),
+                _ => continue,
+            };
+            let current = match stats
+                .peers()
+                .iter()
+                .find(|stats| stats.public_key == peer.public_key)
+            {
+                Some(current) => current,
+                None => {
+                    warn!("Peer {} missing on interface", peer.public_key);
+                    continue;
+                }
+            };
+            let stale = match current.latest_handshake {
+                Some(handshake) => now
+                    .duration_since(handshake)
+                    .map(|age| age > self.timeout)
+                    .unwrap_or(false),
+                None => true,
+            };
+            if !stale {
+                continue;
+            }
+            let addrs = match self.resolver.resolve(host, port).await {
+                Ok(addrs) => addrs,
+                Err(error) => {
+                    warn!(
+                        "Error resolving endpoint {host} of {}: {error}",
+                        peer.public_key
+                    );
+                    continue;
+                }
+            };
+            // hosts resolving to several addresses would otherwise flap between them
+            if current
+                .endpoint
+                .is_some_and(|endpoint| addrs.contains(&endpoint))
+            {
+                continue;
+            }
+            if let Some(addr) = addrs.first() {
+                updates.push((peer.public_key, *addr));
+            }
+        }
+        Ok(updates)
+    }
+
+    /// Check the peers once and update the endpoints that changed.
+    pub async fn run_once(&self) -> Result<Vec<(Pubkey, SocketAddr)>> {
+        let stats = wireguard_stats(self.netns.as_str(), &self.interface).await?;
+        let updates = self.check(&stats, SystemTime::now()).await?;
+        for (peer, addr) in &updates {
+            wireguard_peer_endpoint(&self.netns, &self.interface, peer, *addr).await?;
+        }
+        Ok(updates)
+    }
+
+    /// Check the peers at every interval, forever. Errors are logged and checking
+    /// continues at the next interval.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            if let Err(error) = self.run_once().await {
+                warn!(
+                    "Error resolving endpoints for {} in {}: {error}",
+                    self.interface, self.netns
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+struct FakeResolver(std::collections::HashMap<String, Vec<SocketAddr>>);
+
+#[cfg(test)]
+impl Resolver for FakeResolver {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs = self
+            .0
+            .get(host)
+            .ok_or_else(|| anyhow::anyhow!("Unknown host {host}"))?;
+        Ok(addrs
+            .iter()
+            .map(|addr| SocketAddr::new(addr.ip(), port))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_wireguard_reresolve() {
+    use std::str::FromStr;
+    use std::time::UNIX_EPOCH;
+    let stale_key = "AUWzWgXDl+MUqnpSIOQN8MPpx10TFdEo3zlNxQpyQ2A=";
+    let fresh_key = "EIlS8mtQ8qf8ofIsMsOtpwpqtyFaRgDE5Kvm/DLRdC0=";
+    let static_key = "TxsQfcl4+RSI0RXMvIa1h7/HoeKGiw2EPSa9xjfy8ww=";
+    let unknown_key = "cFnKhp0uB+UMUgERCZEpMdjDmD5c+Ltw4YyoZchIf1w=";
+    let stats = NetworkStats::from_str(&format!(
+        "(none)\t(none)\t51820\toff\n\
+         {stale_key}\t(none)\t1.1.1.1:51820\t10.0.0.2/32\t1000\t0\t0\toff\n\
+         {fresh_key}\t(none)\t2.2.2.2:51820\t10.0.0.3/32\t1900\t0\t0\toff\n\
+         {static_key}\t(none)\t3.3.3.3:51820\t10.0.0.4/32\t0\t0\t0\toff\n\
+         {unknown_key}\t(none)\t4.4.4.4:51820\t10.0.0.5/32\t0\t0\t0\toff\n"
+    ))
+    .unwrap();
+    let peer = |key: &str, endpoint: &str| {
+        let mut peer = WireguardPeerConfig::new(Pubkey::from_str(key).unwrap());
+        peer.endpoint = Some(endpoint.parse().unwrap());
+        peer
+    };
+    let peers = vec![
+        peer(stale_key, "stale.example.com:51820"),
+        peer(fresh_key, "fresh.example.com:51820"),
+        peer(static_key, "3.3.3.3:51820"),
+        peer(unknown_key, "unknown.example.com:51820"),
+    ];
+    let resolver = FakeResolver(
+        [
+            ("stale.example.com", "9.9.9.9:0"),
+            ("fresh.example.com", "8.8.8.8:0"),
+        ]
+        .iter()
+        .map(|(host, addr)| (host.to_string(), vec![addr.parse().unwrap()]))
+        .collect(),
+    );
+    let reresolver = WireguardReresolver::with_resolver("netns", "wg0", &peers, resolver);
+    let now = UNIX_EPOCH + Duration::from_secs(2000);
+
+    // only the stale hostname peer is updated, resolution failures are skipped
+    let updates = reresolver.check(&stats, now).await.unwrap();
+    assert_eq!(
+        updates,
+        vec![(
+            Pubkey::from_str(stale_key).unwrap(),
+            "9.9.9.9:51820".parse().unwrap()
+        )]
+    );
+
+    // no update when the resolved address is already in use
+    let reresolver = reresolver.timeout(Duration::from_secs(10));
+    let stats = NetworkStats::from_str(&format!(
+        "(none)\t(none)\t51820\toff\n\
+         {stale_key}\t(none)\t9.9.9.9:51820\t10.0.0.2/32\t1000\t0\t0\toff\n\
+         {fresh_key}\t(none)\t2.2.2.2:51820\t10.0.0.3/32\t1900\t0\t0\toff\n\
+         {static_key}\t(none)\t3.3.3.3:51820\t10.0.0.4/32\t0\t0\t0\toff\n\
+         {unknown_key}\t(none)\t4.4.4.4:51820\t10.0.0.5/32\t0\t0\t0\toff\n"
+    ))
+    .unwrap();
+    let updates = reresolver.check(&stats, now).await.unwrap();
+    assert_eq!(
+        updates,
+        vec![(
+            Pubkey::from_str(fresh_key).unwrap(),
+            "8.8.8.8:51820".parse().unwrap()
+        )]
+    );
+
+    // peers missing on the interface are skipped, any resolved address is kept
+    let missing_key = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    let peers = vec![
+        peer(missing_key, "missing.example.com:51820"),
+        peer(stale_key, "multi.example.com:51820"),
+    ];
+    let resolver = FakeResolver(
+        [
+            ("missing.example.com", vec!["7.7.7.7:0"]),
+            ("multi.example.com", vec!["6.6.6.6:0", "9.9.9.9:0"]),
+        ]
+        .iter()
+        .map(|(host, addrs)| {
+            let addrs = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+            (host.to_string(), addrs)
+        })
+        .collect(),
+    );
+    let reresolver = WireguardReresolver::with_resolver("netns", "wg0", &peers, resolver)
+        .timeout(Duration::from_secs(10));
+    assert_eq!(reresolver.check(&stats, now).await.unwrap(), vec![]);
+}